@@ -0,0 +1,62 @@
+"use client";
+
+import TokenStore from "@/app/lib/token";
+import { API_BASE_URL } from "@/app/lib/globals";
+import Link from "next/link";
+import { useEffect, useState } from "react";
+
+export default function Home({ searchParams }: { searchParams: { token?: string } }) {
+
+    let [status, setStatus] = useState("Verifying your email...");
+    let [loggedIn, setLoggedIn] = useState(true);
+
+    useEffect(() => {
+        // The token only verifies the address of the user it was sent to
+        if (!TokenStore.getToken()) {
+            setLoggedIn(false);
+            setStatus("Login first, then open the link again.");
+            return;
+        }
+
+        const verify = async () => {
+            try {
+                const res = await fetch(API_BASE_URL + "user/email/verify", {
+                    method: "POST",
+                    headers: {
+                        "Content-Type": "application/json",
+                        "Authorization": TokenStore.getToken(),
+                    },
+                    body: JSON.stringify({
+                        token: searchParams.token ?? "",
+                    })
+                }).then((r) => r.json());
+
+                const err = res.error;
+                if (err) {
+                    setStatus(err.msg);
+                } else {
+                    setStatus(`${res.email} is verified!`);
+                }
+            } catch (error) {
+                if (error instanceof Error) {
+                    setStatus(error.message);
+                }
+            }
+        };
+        verify();
+    }, [searchParams.token]);
+
+    return (
+        <div className="flex flex-col justify-center ml-24 h-screen text-4xl">
+            <h1 className="mb-12">{status}</h1>
+            <h2>
+                <Link
+                    href={loggedIn ? "/chat" : "/auth/login"}
+                    className="underline"
+                >
+                    {loggedIn ? "Back to chat" : "Login"}
+                </Link>
+            </h2>
+        </div>
+    );
+}
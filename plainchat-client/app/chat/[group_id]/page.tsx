@@ -140,6 +140,23 @@ export default function Home({ params }: { params: { group_id: string } }) {
                 });
             }
         });
+        socket.on("admin", (user: string) => {
+            if (!done) {
+                const adminMsg = user + " is now the admin.";
+                const eventMsg: Message = {
+                    msg_type: "Event",
+                    content: adminMsg,
+                    date: ""
+                };
+                setMemberList((prev) => prev?.map((u) => {
+                    if (u.username === user) u.role = "Admin";
+                    return u;
+                }));
+                setMessageList((prev) => {
+                    return [...prev ?? [], eventMsg];
+                });
+            }
+        });
         socket.on("type_start", (user) => {
             if (!done && user !== TokenStore.getTokenOwner()) {
                 setUsersTyping([...usersTyping, user]);
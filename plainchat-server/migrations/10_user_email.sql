@@ -0,0 +1,7 @@
+alter table "users"
+    add column if not exists email varchar(255) unique,
+    add column if not exists email_verified_at timestamp,
+    add column if not exists email_token varchar(255),
+    add column if not exists email_token_expires_at timestamp,
+    add column if not exists last_seen_at timestamp default now() not null,
+    add column if not exists last_digest_at timestamp
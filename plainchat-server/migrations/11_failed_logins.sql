@@ -0,0 +1,11 @@
+create table if not exists "failed_logins" (
+    id uuid primary key default gen_random_uuid(),
+    user_id uuid not null,
+    ip varchar(45),
+    user_agent varchar(255),
+    created_at timestamp default now() not null,
+
+    constraint fk_user foreign key(user_id) references users(id)
+);
+
+create index if not exists idx_failed_logins_user on failed_logins(user_id, created_at)
@@ -0,0 +1,66 @@
+-- A user is in a group once, duplicates keep their highest role
+delete from user_groups as dup
+using user_groups as keep
+where
+    dup.user_id = keep.user_id and
+    dup.group_id = keep.group_id and
+    (dup.role < keep.role or (dup.role = keep.role and dup.id > keep.id));
+
+alter table "user_groups"
+    add constraint uq_user_group unique(user_id, group_id);
+
+-- Deleting a user or group takes everything hanging off it along
+alter table "user_groups"
+    drop constraint fk_user,
+    drop constraint fk_group,
+    add constraint fk_user foreign key(user_id) references users(id) on delete cascade,
+    add constraint fk_group foreign key(group_id) references groups(id) on delete cascade;
+
+alter table "messages"
+    drop constraint fk_group,
+    add constraint fk_group foreign key(receiver_group_id) references groups(id) on delete cascade;
+
+alter table "group_bans"
+    drop constraint fk_user,
+    drop constraint fk_group,
+    add constraint fk_user foreign key(user_id) references users(id) on delete cascade,
+    add constraint fk_group foreign key(group_id) references groups(id) on delete cascade;
+
+alter table "pinned_messages"
+    drop constraint fk_group,
+    drop constraint fk_message,
+    add constraint fk_group foreign key(group_id) references groups(id) on delete cascade,
+    add constraint fk_message foreign key(message_id) references messages(id) on delete cascade;
+
+alter table "mentions"
+    drop constraint fk_message,
+    drop constraint fk_group,
+    drop constraint fk_user,
+    add constraint fk_message foreign key(message_id) references messages(id) on delete cascade,
+    add constraint fk_group foreign key(group_id) references groups(id) on delete cascade,
+    add constraint fk_user foreign key(user_id) references users(id) on delete cascade;
+
+alter table "push_subscriptions"
+    drop constraint fk_user,
+    add constraint fk_user foreign key(user_id) references users(id) on delete cascade;
+
+alter table "failed_logins"
+    drop constraint fk_user,
+    add constraint fk_user foreign key(user_id) references users(id) on delete cascade;
+
+alter table "users"
+    add column if not exists created_at timestamp default now() not null;
+
+alter table "groups"
+    add column if not exists created_at timestamp default now() not null;
+
+alter table "user_groups"
+    add column if not exists created_at timestamp default now() not null;
+
+-- History pages and the cache are read newest first per group
+create index if not exists idx_messages_group_created on messages(receiver_group_id, created_at, id);
+create index if not exists idx_messages_sender on messages(sender_id);
+create index if not exists idx_user_groups_group on user_groups(group_id);
+create index if not exists idx_pinned_messages_group on pinned_messages(group_id);
+create index if not exists idx_mentions_group on mentions(group_id);
+create index if not exists idx_push_subscriptions_user on push_subscriptions(user_id);
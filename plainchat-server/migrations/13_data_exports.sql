@@ -0,0 +1,17 @@
+create type export_status as enum ('pending', 'ready', 'failed');
+
+create table if not exists "data_exports" (
+    id uuid primary key default gen_random_uuid(),
+    user_id uuid not null,
+    status export_status default 'pending' not null,
+    archive bytea,
+    created_at timestamp default now() not null,
+    completed_at timestamp,
+    expires_at timestamp,
+
+    constraint fk_user foreign key(user_id) references users(id) on delete cascade
+);
+
+create index if not exists idx_data_exports_user on data_exports(user_id, created_at);
+create index if not exists idx_data_exports_expires on data_exports(expires_at);
+create unique index if not exists uq_data_exports_pending on data_exports(user_id) where status = 'pending'
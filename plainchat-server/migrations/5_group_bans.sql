@@ -0,0 +1,13 @@
+create table if not exists "group_bans" (
+    id uuid primary key default gen_random_uuid(),
+    group_id uuid not null,
+    user_id uuid not null,
+    banned_by uuid references users(id) on delete set null,
+    reason text,
+    expires_at timestamp,
+    created_at timestamp default now() not null,
+
+    constraint fk_group foreign key(group_id) references groups(id),
+    constraint fk_user foreign key(user_id) references users(id),
+    constraint uq_group_ban unique(group_id, user_id)
+)
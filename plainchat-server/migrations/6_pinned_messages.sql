@@ -0,0 +1,11 @@
+create table if not exists "pinned_messages" (
+    id uuid primary key default gen_random_uuid(),
+    group_id uuid not null,
+    message_id uuid not null,
+    pinned_by uuid references users(id) on delete set null,
+    created_at timestamp default now() not null,
+
+    constraint fk_group foreign key(group_id) references groups(id),
+    constraint fk_message foreign key(message_id) references messages(id),
+    constraint uq_pinned_message unique(message_id)
+)
@@ -0,0 +1,20 @@
+create type mention_policy as enum ('everyone', 'admins', 'nobody');
+
+alter table "groups" 
+    add column if not exists mention_policy mention_policy not null default 'admins';
+
+create table if not exists "mentions" (
+    id uuid primary key default gen_random_uuid(),
+    message_id uuid not null,
+    group_id uuid not null,
+    user_id uuid not null,
+    read_at timestamp,
+    created_at timestamp default now() not null,
+
+    constraint fk_message foreign key(message_id) references messages(id),
+    constraint fk_group foreign key(group_id) references groups(id),
+    constraint fk_user foreign key(user_id) references users(id),
+    constraint uq_mention unique(message_id, user_id)
+);
+
+create index if not exists idx_mentions_user on mentions(user_id, read_at)
@@ -0,0 +1,6 @@
+create type notification_level as enum ('all', 'mentions', 'none');
+
+alter table "user_groups"
+    add column if not exists notify notification_level not null default 'all',
+    add column if not exists muted_until timestamp,
+    add column if not exists last_read_at timestamp default now() not null
@@ -0,0 +1,12 @@
+create table if not exists "push_subscriptions" (
+    id uuid primary key default gen_random_uuid(),
+    user_id uuid not null,
+    endpoint text not null unique,
+    p256dh text not null,
+    auth text not null,
+    failures integer default 0 not null,
+    created_at timestamp default now() not null,
+    last_used_at timestamp,
+
+    constraint fk_user foreign key(user_id) references users(id)
+)
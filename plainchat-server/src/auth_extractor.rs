@@ -1,18 +1,16 @@
-use core::time;
-use std::{fmt::Debug, str::FromStr, sync::LazyLock, time::UNIX_EPOCH};
+use std::{fmt::Debug, str::FromStr, time::UNIX_EPOCH};
 
 use axum::{async_trait, extract::{FromRef, FromRequestParts}, http::request::Parts, RequestPartsExt};
 use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
-use redis::{aio::MultiplexedConnection, AsyncCommands};
 use serde::{Deserialize, Serialize};
-use tracing::{event, Level};
 use uuid::Uuid;
 
 use crate::{error::AppError, util::redis_store, AppState};
+use crate::config::AuthConfig;
 use crate::error::Result;
+use crate::util::sqlx_ext::Traced;
 
-pub const TOKEN_EXPIRE_SECS: u64 = 7 * 24 * 3600;
 struct Keys {
     encoding: EncodingKey,
     decoding: DecodingKey,
@@ -27,10 +25,6 @@ impl Keys {
     }
 }
 
-static KEYS: LazyLock<Keys> = LazyLock::new(|| {
-    let secret = dotenv::var("JWT_SECRET").expect("JWT_SECRET must be set.");
-    Keys::new(secret.as_bytes())
-});
 
 
 #[derive(Clone, Debug)]
@@ -38,30 +32,32 @@ pub struct AuthContext(pub Uuid);
 
 
 impl AuthContext {
-    pub fn generate_jwt(&self) -> String {
+    pub fn generate_jwt(&self, config: &AuthConfig) -> String {
         let now= std::time::SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Duration should be convertible to epoch")
             .as_secs();
 
-        let now_plus_week = now.checked_add(TOKEN_EXPIRE_SECS)
-            .expect("System curr time should be addable with a week.");
+        let expires_at = now.checked_add(config.token_lifetime_secs)
+            .expect("System curr time should be addable with the token lifetime.");
 
 
         let claims = TokenClaims {
             sub: self.0.to_string(),
             iat: now as usize,
-            exp: now_plus_week as usize
+            exp: expires_at as usize
         };
 
         let token_type = "Bearer";
-        let jwt = encode(&Header::default(), &claims, &KEYS.encoding).unwrap();
+        let keys = Keys::new(config.jwt_secret.as_bytes());
+        let jwt = encode(&Header::default(), &claims, &keys.encoding).unwrap();
 
         format!("{token_type} {jwt}")
     }
 
-    pub fn verify_jwt(token: &str) -> Result<TokenData<TokenClaims>> {
-        decode::<TokenClaims>(token, &KEYS.decoding, &Validation::default())
+    pub fn verify_jwt(token: &str, config: &AuthConfig) -> Result<TokenData<TokenClaims>> {
+        let keys = Keys::new(config.jwt_secret.as_bytes());
+        decode::<TokenClaims>(token, &keys.decoding, &Validation::default())
             .map_err(|_| AppError::InvalidToken)
     }
 }
@@ -102,8 +98,20 @@ where
             match uuid_cache {
                 Some(uuid) => uuid, 
                 None => {
-                    let jwt = Self::verify_jwt(bearer.token())?;
-                    Uuid::from_str(&jwt.claims.sub).expect("JWT sub should be convertible to Uuid")
+                    let jwt = Self::verify_jwt(bearer.token(), &state.config.auth)?;
+                    let user_id = Uuid::from_str(&jwt.claims.sub).map_err(|_| AppError::InvalidToken)?;
+
+                    // Tokens outlive the accounts they were issued for
+                    let exists = sqlx::query_scalar!(
+                        r#"SELECT EXISTS(SELECT 1 FROM users WHERE id = $1) AS "exists!""#,
+                        user_id)
+                        .fetch_one(&state.db)
+                        .traced("auth_context")
+                        .await?;
+                    if !exists {
+                        return Err(AppError::InvalidToken);
+                    }
+                    user_id
                 }
             }
         ))
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use sqlx::PgPool;
+use tracing::{error, event, info, Level};
+use uuid::Uuid;
+
+use crate::config::DigestConfig;
+use crate::error::Result;
+use crate::mail::{Mail, Mailer};
+use crate::util::redis_store::{self, RedisStore};
+use crate::util::sqlx_ext::Traced;
+
+const DIGEST_MAX_MENTIONS: i64 = 20;
+// Newest unread messages quoted per group, the rest are only counted
+const DIGEST_MESSAGES_PER_GROUP: i64 = 5;
+
+pub fn spawn(db: PgPool, redis: RedisStore, mailer: Mailer, config: &DigestConfig) {
+    // Bounded by the config validation
+    let offline_secs = config.offline_secs as i64;
+    let interval = Duration::from_secs(config.interval_secs);
+    info!("Sending digests to users offline for {offline_secs}s");
+
+    tokio::spawn(async move {
+        let mut redis = redis;
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = send_digests(&db, &mut redis, &mailer, offline_secs).await {
+                error!("Unable to send digests: {e:?}");
+            }
+        }
+    });
+}
+
+async fn send_digests(
+    db: &PgPool,
+    redis: &mut RedisStore,
+    mailer: &Mailer,
+    offline_secs: i64
+) -> Result<()> {
+    // A digest goes out at most once per offline period, and again only
+    // if the user is still away a full period later
+    let users = sqlx::query!(r#"
+        SELECT
+            id,
+            username,
+            email AS "email!",
+            last_digest_at
+        FROM users
+        WHERE
+            email IS NOT NULL AND
+            email_verified_at IS NOT NULL AND
+            last_seen_at < now() - $1 * interval '1 second' AND
+            (
+                last_digest_at IS NULL OR
+                last_digest_at < GREATEST(last_seen_at, now() - $1 * interval '1 second')
+            )
+    "#, offline_secs as f64)
+        .fetch_all(db)
+        .traced("send_digests")
+        .await?;
+
+    for user in users {
+        if redis_store::is_online(redis, &user.username).await == Some(true) {
+            continue;
+        }
+
+        let Some(body) = digest_body(db, user.id, &user.username, user.last_digest_at).await? else {
+            continue;
+        };
+
+        let mail = Mail {
+            to: user.email,
+            subject: "Unread messages on Plainchat".to_owned(),
+            body,
+        };
+
+        if let Err(e) = mailer.send(mail).await {
+            error!("Unable to send digest to {}: {e:?}", user.username);
+            continue;
+        }
+
+        event!(Level::TRACE, "Sent digest to {}", user.username);
+        sqlx::query!("UPDATE users SET last_digest_at = now() WHERE id = $1", user.id)
+            .execute(db)
+            .traced("send_digests")
+            .await?;
+    }
+
+    Ok(())
+}
+
+// Builds the plain text digest, None when there is nothing new to report.
+async fn digest_body(
+    db: &PgPool,
+    user_id: Uuid,
+    username: &str,
+    since: Option<chrono::NaiveDateTime>
+) -> Result<Option<String>> {
+    let groups = sqlx::query!(r#"
+        SELECT
+            groups.id,
+            groups.name,
+            (
+                SELECT COUNT(*)
+                FROM messages
+                WHERE
+                    messages.receiver_group_id = ug.group_id AND
+                    messages.msg_type = 'normal' AND
+                    messages.created_at > GREATEST(ug.last_read_at, $2) AND
+                    messages.sender_id IS DISTINCT FROM $1
+            ) AS "unread!"
+        FROM user_groups AS ug
+        INNER JOIN groups
+        ON groups.id = ug.group_id
+        WHERE
+            ug.user_id = $1 AND
+            ug.notify = 'all' AND
+            (ug.muted_until IS NULL OR ug.muted_until <= now())
+        ORDER BY groups.name
+    "#, user_id, since)
+        .fetch_all(db)
+        .traced("digest_body")
+        .await?;
+
+    let messages = sqlx::query!(r#"
+        SELECT
+            group_id AS "group_id!",
+            sender AS "sender?",
+            content AS "content!",
+            created_at AS "created_at!"
+        FROM (
+            SELECT
+                msgs.receiver_group_id AS group_id,
+                sender.username AS sender,
+                msgs.content,
+                msgs.created_at,
+                msgs.id,
+                ROW_NUMBER() OVER (
+                    PARTITION BY msgs.receiver_group_id
+                    ORDER BY msgs.created_at DESC, msgs.id DESC
+                ) AS rank
+            FROM user_groups AS ug
+            INNER JOIN messages AS msgs
+            ON msgs.receiver_group_id = ug.group_id
+            LEFT JOIN users AS sender
+            ON sender.id = msgs.sender_id
+            WHERE
+                ug.user_id = $1 AND
+                ug.notify = 'all' AND
+                (ug.muted_until IS NULL OR ug.muted_until <= now()) AND
+                msgs.msg_type = 'normal' AND
+                msgs.created_at > GREATEST(ug.last_read_at, $2) AND
+                msgs.sender_id IS DISTINCT FROM $1
+        ) AS unread
+        WHERE rank <= $3
+        ORDER BY created_at, id
+    "#, user_id, since, DIGEST_MESSAGES_PER_GROUP)
+        .fetch_all(db)
+        .traced("digest_body")
+        .await?;
+
+    let mut messages_by_group = HashMap::<Uuid, Vec<_>>::new();
+    for msg in messages {
+        messages_by_group.entry(msg.group_id).or_default().push(msg);
+    }
+
+    let mentions = sqlx::query!(r#"
+        SELECT
+            groups.name AS group_name,
+            sender.username AS "sender?",
+            msgs.content
+        FROM mentions
+        INNER JOIN messages AS msgs
+        ON msgs.id = mentions.message_id
+        INNER JOIN groups
+        ON groups.id = mentions.group_id
+        INNER JOIN user_groups AS ug
+        ON
+            ug.user_id = mentions.user_id AND
+            ug.group_id = mentions.group_id
+        LEFT JOIN users AS sender
+        ON sender.id = msgs.sender_id
+        WHERE
+            mentions.user_id = $1 AND
+            mentions.read_at IS NULL AND
+            ($2::timestamp IS NULL OR mentions.created_at > $2) AND
+            ug.notify <> 'none' AND
+            (ug.muted_until IS NULL OR ug.muted_until <= now())
+        ORDER BY mentions.created_at
+        LIMIT $3
+    "#, user_id, since, DIGEST_MAX_MENTIONS)
+        .fetch_all(db)
+        .traced("digest_body")
+        .await?;
+
+    let groups = groups.into_iter()
+        .filter(|g| g.unread > 0)
+        .collect::<Vec<_>>();
+
+    if groups.is_empty() && mentions.is_empty() {
+        return Ok(None);
+    }
+
+    let mut body = format!("Hi {username},\n\nHere is what you missed while you were away.\n");
+
+    if !groups.is_empty() {
+        body.push_str("\nUnread messages:\n");
+        for g in groups {
+            let _ = writeln!(body, "\n  {} ({} unread):", g.name, g.unread);
+            let messages = messages_by_group.remove(&g.id).unwrap_or_default();
+            let more = g.unread - messages.len() as i64;
+            for m in messages {
+                let sender = m.sender.as_deref().unwrap_or("DELETED ACCOUNT");
+                let date = m.created_at.format("%Y-%m-%d %H:%M UTC");
+                let _ = writeln!(body, "    [{date}] {sender}: {}", m.content);
+            }
+            if more > 0 {
+                let _ = writeln!(body, "    ...and {more} more");
+            }
+        }
+    }
+
+    if !mentions.is_empty() {
+        body.push_str("\nYou were mentioned:\n");
+        for m in mentions {
+            let sender = m.sender.as_deref().unwrap_or("DELETED ACCOUNT");
+            let _ = writeln!(body, "  {sender} in {}: {}", m.group_name, m.content);
+        }
+    }
+
+    Ok(Some(body))
+}
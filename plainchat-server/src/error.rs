@@ -1,10 +1,14 @@
+use std::time::Duration;
+
+use axum::http::header::RETRY_AFTER;
 use axum::http::StatusCode;
 use axum::Json;
 use axum::response::{IntoResponse, Response};
-use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use thiserror::Error;
-use tracing::{event, warn, Level};
+use tracing::{event, Level};
+
+use crate::validation::FieldErrors;
 
 pub type Result<T, E = AppError> = std::result::Result<T, E>;
 
@@ -40,25 +44,58 @@ pub enum AppError {
     #[error("invalid jwt token")]
     InvalidToken,
 
+    #[error("invalid input")]
+    Validation(FieldErrors),
+
+    #[error("{msg}")]
+    Rejected {
+        status: StatusCode,
+        msg: String
+    },
+
+    #[error("too many requests")]
+    RateLimited {
+        retry_after: Duration
+    },
+
     #[error("anyhow error no explain")]
     Anyhow(#[from] anyhow::Error)
 }
 
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
+impl AppError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Sqlx(_) => "sqlx",
+            AppError::Redis(_) => "redis",
+            AppError::WrongCredentials(_) => "wrong_credentials",
+            AppError::AlreadyExists { .. } => "already_exists",
+            AppError::DoesNotExist { .. } => "does_not_exist",
+            AppError::ForbiddenAction => "forbidden_action",
+            AppError::MissingToken => "missing_token",
+            AppError::InvalidToken => "invalid_token",
+            AppError::Validation(_) => "validation",
+            AppError::Rejected { .. } => "rejected",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Anyhow(_) => "anyhow",
+        }
+    }
 
-        warn!("CUSTOM ERR EXECUTING...");
+    /// Logs and counts the error, returning the status and `{"error": {...}}`
+    /// body to answer with. Shared by http responses and socket acks.
+    pub fn report(&self) -> (StatusCode, Value) {
+        event!(Level::DEBUG, kind = self.kind(), "Request failed: {self}");
+        metrics::counter!("app_errors_total", "kind" => self.kind()).increment(1);
 
-        let err: (StatusCode, Json<Value>) = match &self {
+        match self {
             AppError::WrongCredentials(desc) =>
                 (
                     StatusCode::UNAUTHORIZED,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": desc.to_owned().unwrap_or(self.to_string())
                         }
-                    }))
+                    })
                 ),
             AppError::DoesNotExist {
                 target_type: _, 
@@ -66,92 +103,141 @@ impl IntoResponse for AppError {
             } =>
                 (
                     StatusCode::NOT_FOUND,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": self.to_string()
                         }
-                    }))
+                    })
                 ),
             AppError::AlreadyExists {
                 target_type: _, 
                 data: _
             } => (
                 StatusCode::CONFLICT,
-                Json(json!({
+                json!({
                     "error": {
                         "msg": self.to_string()
                     }
-                }))
+                })
             ),
             AppError::ForbiddenAction =>
                 (
                     StatusCode::FORBIDDEN,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": self.to_string()
                         }
-                    }))
+                    })
                 ),
             AppError::MissingToken =>
                 (
                     StatusCode::UNAUTHORIZED,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": self.to_string(),
                             "token": "missing",
                         }
-                    }))
+                    })
                 ),
             AppError::InvalidToken =>
                 (
                     StatusCode::BAD_REQUEST,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": self.to_string(),
                             "token": "invalid"
                         }
-                    }))
+                    })
+                ),
+            AppError::Validation(fields) =>
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    json!({
+                        "error": {
+                            "msg": self.to_string(),
+                            "fields": fields,
+                        }
+                    })
+                ),
+            AppError::Rejected { status, msg } =>
+                (
+                    *status,
+                    json!({
+                        "error": {
+                            "msg": msg,
+                        }
+                    })
+                ),
+            AppError::RateLimited { retry_after } =>
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    json!({
+                        "error": {
+                            "msg": self.to_string(),
+                            "retry_after": retry_after_secs(*retry_after),
+                        }
+                    })
                 ),
             AppError::Sqlx(e) => {
                 event!(Level::ERROR, "DB ERROR: {e:?}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": "Server encountered some error.",
                             "server": "db",
                         }
-                    }))
+                    })
                 )
             },
             AppError::Redis(e) => {
                 event!(Level::ERROR, "REDIS ERROR: {e:?}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": "Server encountered some error.",
                             "server": "redis",
                         }
-            }))
+                    })
                 )
             }
             AppError::Anyhow(e) => {
                 tracing::error!("{e:?}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({
+                    json!({
                         "error": {
                             "msg": "Server encountered some error.",
                             "server": "unknown",
                         }
-                    }))
+                    })
                 )
             }
-        };
+        }
+    }
+}
 
-        err.into_response()
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, mut body) = self.report();
+
+        // Lets a user report quote the id that leads to the log lines
+        if let Some(request_id) = crate::telemetry::request_id() {
+            body["error"]["request_id"] = Value::String(request_id);
+        }
+
+        let mut res = (status, Json(body)).into_response();
+        if let AppError::RateLimited { retry_after } = self {
+            res.headers_mut().insert(RETRY_AFTER, retry_after_secs(retry_after).into());
+        }
+        res
     }
 }
 
+// Retry-After only takes whole seconds, rounded up so a retry isn't early
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
 
@@ -0,0 +1,330 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::config::ExportConfig;
+use crate::error::Result;
+use crate::models::{NotificationLevel, UserRole};
+use crate::util::sqlx_ext::Traced;
+
+const EXPORT_QUEUE_SIZE: usize = 256;
+
+/// Handle to the export worker, which builds one archive at a time.
+#[derive(Clone, Debug)]
+pub struct ExportQueue {
+    tx: mpsc::Sender<Uuid>,
+}
+
+impl ExportQueue {
+    /// A job that doesn't fit in the queue stays pending until the next
+    /// cleanup picks it up again.
+    pub fn enqueue(&self, export_id: Uuid) {
+        if let Err(e) = self.tx.try_send(export_id) {
+            warn!("Unable to queue export {export_id}: {e}");
+        }
+    }
+}
+
+pub fn spawn(db: PgPool, config: ExportConfig) -> ExportQueue {
+    let (tx, rx) = mpsc::channel(EXPORT_QUEUE_SIZE);
+    let queue = ExportQueue { tx };
+    info!("Keeping data exports for {}s", config.archive_ttl_secs);
+
+    tokio::spawn(run(db, config, queue.clone(), rx));
+    queue
+}
+
+async fn run(db: PgPool, config: ExportConfig, queue: ExportQueue, mut rx: mpsc::Receiver<Uuid>) {
+    // Jobs left pending by the last run are picked up right away, later
+    // on only those that have been waiting a whole interval
+    let mut stale_after = 0;
+    let mut cleanup = tokio::time::interval(Duration::from_secs(config.cleanup_interval_secs));
+    loop {
+        tokio::select! {
+            Some(export_id) = rx.recv() => {
+                if let Err(e) = build_export(&db, &config, export_id).await {
+                    error!("Unable to build export {export_id}: {e:?}");
+                    let _ = fail_export(&db, &config, export_id).await;
+                }
+            },
+            _ = cleanup.tick() => {
+                if let Err(e) = clean_up(&db, &queue, stale_after).await {
+                    error!("Unable to clean up exports: {e:?}");
+                }
+                stale_after = config.cleanup_interval_secs as i64;
+            },
+        }
+    }
+}
+
+async fn build_export(db: &PgPool, config: &ExportConfig, export_id: Uuid) -> Result<()> {
+    // Queued twice or already handled
+    let Some(job) = sqlx::query!(r#"
+        SELECT user_id
+        FROM data_exports
+        WHERE
+            id = $1 AND
+            status = 'pending'
+    "#, export_id)
+        .fetch_optional(db)
+        .traced("build_export")
+        .await?
+    else {
+        return Ok(());
+    };
+
+    let archive = collect_archive(db, job.user_id).await?;
+    let archive = serde_json::to_vec_pretty(&archive)
+        .context("Export archive should be serializable")?;
+
+    sqlx::query!(r#"
+        UPDATE data_exports
+        SET
+            status = 'ready',
+            archive = $2,
+            completed_at = now(),
+            expires_at = now() + $3 * interval '1 second'
+        WHERE
+            id = $1 AND
+            status = 'pending'
+    "#, export_id, archive, config.archive_ttl_secs as i64)
+        .execute(db)
+        .traced("build_export")
+        .await?;
+
+    info!("Export {export_id} is ready, {} bytes", archive.len());
+    Ok(())
+}
+
+// A failed job is kept around for the user to see, as long as an archive would
+async fn fail_export(db: &PgPool, config: &ExportConfig, export_id: Uuid) -> Result<()> {
+    sqlx::query!(r#"
+        UPDATE data_exports
+        SET
+            status = 'failed',
+            completed_at = now(),
+            expires_at = now() + $2 * interval '1 second'
+        WHERE
+            id = $1 AND
+            status = 'pending'
+    "#, export_id, config.archive_ttl_secs as i64)
+        .execute(db)
+        .traced("fail_export")
+        .await?;
+    Ok(())
+}
+
+async fn clean_up(db: &PgPool, queue: &ExportQueue, stale_after: i64) -> Result<()> {
+    let expired = sqlx::query!("DELETE FROM data_exports WHERE expires_at <= now()")
+        .execute(db)
+        .traced("clean_up")
+        .await?
+        .rows_affected();
+    if expired > 0 {
+        info!("Deleted {expired} expired exports");
+    }
+
+    let stale = sqlx::query!(r#"
+        SELECT id
+        FROM data_exports
+        WHERE
+            status = 'pending' AND
+            created_at <= now() - $1::bigint * interval '1 second'
+        ORDER BY created_at
+    "#, stale_after)
+        .fetch_all(db)
+        .traced("clean_up")
+        .await?;
+    for job in stale {
+        queue.enqueue(job.id);
+    }
+
+    Ok(())
+}
+
+/// Everything the server keeps about a user. Tokens aren't part of it,
+/// they're only ever held as given out.
+#[derive(Serialize)]
+struct Archive {
+    generated_at: NaiveDateTime,
+    profile: Profile,
+    memberships: Vec<Membership>,
+    messages: Vec<SentMessage>,
+    mentions: Vec<Mention>,
+    bans: Vec<Ban>,
+    push_subscriptions: Vec<PushSubscription>,
+    failed_logins: Vec<FailedLogin>,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    id: Uuid,
+    username: String,
+    email: Option<String>,
+    email_verified_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct Membership {
+    group_id: Uuid,
+    group_name: String,
+    role: UserRole,
+    notify: NotificationLevel,
+    muted_until: Option<NaiveDateTime>,
+    last_read_at: NaiveDateTime,
+    joined_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct SentMessage {
+    id: Uuid,
+    group_id: Uuid,
+    group_name: String,
+    content: String,
+    date: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct Mention {
+    message_id: Uuid,
+    group_id: Uuid,
+    created_at: NaiveDateTime,
+    read_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+struct Ban {
+    group_id: Uuid,
+    group_name: String,
+    reason: Option<String>,
+    created_at: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+}
+
+// The keys belong to the browser, the endpoint is enough to recognize it
+#[derive(Serialize)]
+struct PushSubscription {
+    endpoint: String,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+struct FailedLogin {
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+async fn collect_archive(db: &PgPool, user_id: Uuid) -> Result<Archive> {
+    let profile = sqlx::query_as!(Profile, r#"
+        SELECT id, username, email, email_verified_at, created_at, last_seen_at
+        FROM users
+        WHERE id = $1
+    "#, user_id)
+        .fetch_one(db)
+        .traced("collect_archive")
+        .await?;
+
+    let memberships = sqlx::query_as!(Membership, r#"
+        SELECT
+            groups.id AS group_id,
+            groups.name AS group_name,
+            ug.role AS "role: UserRole",
+            ug.notify AS "notify: NotificationLevel",
+            ug.muted_until,
+            ug.last_read_at,
+            ug.created_at AS joined_at
+        FROM user_groups AS ug
+        INNER JOIN groups
+        ON groups.id = ug.group_id
+        WHERE ug.user_id = $1
+        ORDER BY ug.created_at
+    "#, user_id)
+        .fetch_all(db)
+        .traced("collect_archive")
+        .await?;
+
+    let messages = sqlx::query_as!(SentMessage, r#"
+        SELECT
+            msgs.id,
+            groups.id AS group_id,
+            groups.name AS group_name,
+            msgs.content,
+            msgs.created_at AS date
+        FROM messages AS msgs
+        INNER JOIN groups
+        ON groups.id = msgs.receiver_group_id
+        WHERE msgs.sender_id = $1
+        ORDER BY msgs.created_at, msgs.id
+    "#, user_id)
+        .fetch_all(db)
+        .traced("collect_archive")
+        .await?;
+
+    let mentions = sqlx::query_as!(Mention, r#"
+        SELECT message_id, group_id, created_at, read_at
+        FROM mentions
+        WHERE user_id = $1
+        ORDER BY created_at
+    "#, user_id)
+        .fetch_all(db)
+        .traced("collect_archive")
+        .await?;
+
+    let bans = sqlx::query_as!(Ban, r#"
+        SELECT
+            groups.id AS group_id,
+            groups.name AS group_name,
+            bans.reason,
+            bans.created_at,
+            bans.expires_at
+        FROM group_bans AS bans
+        INNER JOIN groups
+        ON groups.id = bans.group_id
+        WHERE bans.user_id = $1
+        ORDER BY bans.created_at
+    "#, user_id)
+        .fetch_all(db)
+        .traced("collect_archive")
+        .await?;
+
+    let push_subscriptions = sqlx::query_as!(PushSubscription, r#"
+        SELECT endpoint, created_at, last_used_at
+        FROM push_subscriptions
+        WHERE user_id = $1
+        ORDER BY created_at
+    "#, user_id)
+        .fetch_all(db)
+        .traced("collect_archive")
+        .await?;
+
+    let failed_logins = sqlx::query_as!(FailedLogin, r#"
+        SELECT ip, user_agent, created_at
+        FROM failed_logins
+        WHERE user_id = $1
+        ORDER BY created_at
+    "#, user_id)
+        .fetch_all(db)
+        .traced("collect_archive")
+        .await?;
+
+    Ok(Archive {
+        generated_at: chrono::Utc::now().naive_utc(),
+        profile,
+        memberships,
+        messages,
+        mentions,
+        bans,
+        push_subscriptions,
+        failed_logins,
+    })
+}
@@ -0,0 +1,150 @@
+use std::error::Error;
+
+use axum::async_trait;
+use axum::extract::path::ErrorKind;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, RawPathParams, Request};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::AppError;
+use crate::validation::FieldErrors;
+
+// Drop-in replacements for axum's extractors that reject with an AppError,
+// so a bad request gets the same json error envelope as everything else.
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::Json::<T>::from_request(req, state).await {
+            Ok(axum::Json(value)) => Ok(Json(value)),
+            Err(rejection) => Err(json_rejection(rejection)),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Path::<T>::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(value)) => Ok(Path(value)),
+            Err(rejection) => {
+                let params = RawPathParams::from_request_parts(parts, state).await
+                    .map(|params| params.iter().map(|(key, _)| key.to_owned()).collect())
+                    .unwrap_or_default();
+                Err(path_rejection(rejection, params))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Query::<T>::from_request_parts(parts, state).await {
+            Ok(axum::extract::Query(value)) => Ok(Query(value)),
+            Err(QueryRejection::FailedToDeserializeQueryString(e)) => {
+                let mut errors = FieldErrors::default();
+                let msg = e.source()
+                    .and_then(Error::source)
+                    .map_or_else(|| e.body_text(), ToString::to_string);
+                errors.add("query", msg);
+                Err(AppError::Validation(errors))
+            },
+            Err(rejection) => Err(rejected(rejection.status(), rejection.body_text())),
+        }
+    }
+}
+
+fn json_rejection(rejection: JsonRejection) -> AppError {
+    let JsonRejection::JsonDataError(e) = rejection else {
+        return rejected(rejection.status(), rejection.body_text());
+    };
+
+    // The deserializer error carries the path to the offending field
+    let mut errors = FieldErrors::default();
+    match find_source::<serde_path_to_error::Error<serde_json::Error>>(&e) {
+        Some(e) => {
+            let field = e.path().to_string();
+            let field = if field == "." { "body" } else { &field };
+            errors.add(field, e.inner().to_string());
+        },
+        None => errors.add("body", e.body_text()),
+    }
+    AppError::Validation(errors)
+}
+
+fn path_rejection(rejection: PathRejection, params: Vec<String>) -> AppError {
+    let PathRejection::FailedToDeserializePathParams(e) = rejection else {
+        return rejected(rejection.status(), rejection.body_text());
+    };
+
+    let mut errors = FieldErrors::default();
+    let single = || params.first().filter(|_| params.len() == 1).map_or("path", String::as_str);
+    match e.into_kind() {
+        ErrorKind::ParseErrorAtKey { key, value, expected_type } =>
+            errors.add(&key, invalid_value(&value, expected_type)),
+        ErrorKind::ParseErrorAtIndex { index, value, expected_type } =>
+            errors.add(params.get(index).map_or("path", String::as_str), invalid_value(&value, expected_type)),
+        ErrorKind::ParseError { value, expected_type } =>
+            errors.add(single(), invalid_value(&value, expected_type)),
+        // Ids fail in their own deserializer, without saying which param
+        kind => errors.add(single(), kind.to_string()),
+    }
+    AppError::Validation(errors)
+}
+
+fn invalid_value(value: &str, expected_type: &str) -> String {
+    let expected_type = expected_type.rsplit("::").next().unwrap_or(expected_type);
+    format!("`{value}` is not a valid {}", expected_type.to_lowercase())
+}
+
+fn rejected(status: axum::http::StatusCode, msg: String) -> AppError {
+    AppError::Rejected { status, msg }
+}
+
+fn find_source<'a, E: Error + 'static>(err: &'a (dyn Error + 'static)) -> Option<&'a E> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<E>() {
+            return Some(err);
+        }
+        source = err.source();
+    }
+    None
+}
@@ -0,0 +1,184 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::response::IntoResponse;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::HeaderValue;
+use axum::{middleware, Extension, Router};
+use axum::routing::get;
+use metrics_exporter_prometheus::PrometheusHandle;
+use socketioxide::SocketIo;
+use sqlx::PgPool;
+use sqlx::postgres::PgPoolOptions;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tower::ServiceBuilder;
+use tower_http::cors::Any;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tracing::{error, info, warn};
+
+mod error;
+pub mod routes;
+pub mod models;
+pub mod seed;
+mod ws;
+mod push;
+mod mail;
+mod digest;
+pub mod telemetry;
+pub mod config;
+mod rate_limit;
+mod login_guard;
+mod validation;
+mod extract;
+mod export;
+
+pub mod util;
+pub mod auth_extractor;
+
+#[derive(Clone, Debug)]
+pub struct AppState {
+    pub config: Arc<config::Config>,
+    pub db: PgPool,
+    pub redis: util::redis_store::RedisStore,
+    push: push::PushQueue,
+    mailer: mail::Mailer,
+    validator: Arc<validation::Validator>,
+    exports: export::ExportQueue,
+}
+
+impl AppState {
+    /// Connects to postgres and redis, runs pending migrations and starts
+    /// the background workers.
+    pub async fn connect(config: Arc<config::Config>) -> anyhow::Result<Self> {
+        let db = PgPoolOptions::new()
+            .max_connections(config.database.max_connections)
+            .connect(&config.database.url).await?;
+
+        let redis = util::redis_store::RedisStore::connect(&config).await?;
+
+        sqlx::migrate!("./migrations")
+            .run(&db)
+            .await?;
+
+        let push = push::spawn_worker(db.clone(), redis.clone(), config.push.clone())?;
+
+        let mailer = match mail::from_config(&config.mail)? {
+            Some(mailer) => {
+                digest::spawn(db.clone(), redis.clone(), mailer.clone(), &config.digest);
+                mailer
+            },
+            None => Arc::new(mail::LogMailer),
+        };
+
+        let validator = Arc::new(validation::Validator::new(&config.validation)?);
+
+        let exports = export::spawn(db.clone(), config.export.clone());
+
+        Ok(Self {config, db, redis, push, mailer, validator, exports})
+    }
+}
+
+/// Every public route and the socket layer, with the socket server to shut
+/// down.
+pub fn app(state: AppState) -> (Router, SocketIo) {
+    let cors = cors_layer(&state.config.server.cors_origins);
+    let (socket_layer, io) = ws::layer(state.clone());
+
+    let app = Router::new()
+        .route("/", get(index_handler))
+        .merge(Router::new()
+            .nest("/api/user", routes::user::router())
+            .nest("/api/group", routes::group::router())
+            .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_http)))
+        .layer(Extension(io.clone()))
+        .layer(middleware::from_fn(telemetry::track_http))
+        .layer(
+            ServiceBuilder::new()
+                .layer(SetRequestIdLayer::new(telemetry::REQUEST_ID_HEADER.clone(), MakeRequestUuid))
+                .layer(TraceLayer::new_for_http().make_span_with(telemetry::http_span))
+                .layer(PropagateRequestIdLayer::new(telemetry::REQUEST_ID_HEADER.clone()))
+                .layer(middleware::from_fn(telemetry::scope_request_id))
+                .layer(cors)
+                .layer(socket_layer)
+        )
+        .with_state(state);
+
+    (app, io)
+}
+
+/// Health checks and metrics, kept off the public listener since they
+/// aren't authenticated.
+pub fn internal_app(state: AppState, io: SocketIo, metrics: PrometheusHandle) -> Router {
+    Router::new()
+        .nest("/health", routes::health::router())
+        .merge(telemetry::metrics_router(metrics))
+        .layer(Extension(io))
+        .with_state(state)
+}
+
+/// Serves the app until `shutdown` resolves, then drains sockets and
+/// gives in-flight requests `server.shutdown_timeout_secs` to finish.
+/// `internal_listener` serves the internal app until the process exits.
+pub async fn serve(
+    listener: TcpListener,
+    internal_listener: TcpListener,
+    state: AppState,
+    metrics: PrometheusHandle,
+    shutdown: impl Future<Output = ()> + Send + 'static
+) -> anyhow::Result<()> {
+    let shutdown_timeout = Duration::from_secs(state.config.server.shutdown_timeout_secs);
+    let (app, io) = app(state.clone());
+    let internal_app = internal_app(state.clone(), io.clone(), metrics);
+
+    info!("Listening on {}", listener.local_addr()?);
+    info!("Serving health checks and metrics on {}", internal_listener.local_addr()?);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(internal_listener, internal_app).await {
+            error!("Internal listener failed: {e}");
+        }
+    });
+
+    let draining = Arc::new(Notify::new());
+    let shutdown = {
+        let draining = draining.clone();
+        let mut state = state;
+        async move {
+            shutdown.await;
+            info!("Shutting down, draining connections");
+            ws::shutdown(&io, &mut state).await;
+            draining.notify_one();
+        }
+    };
+
+    tokio::select! {
+        res = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(shutdown) => res?,
+        _ = async {
+            draining.notified().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => warn!("In-flight requests did not finish within {shutdown_timeout:?}"),
+    }
+
+    Ok(())
+}
+
+async fn index_handler() -> impl IntoResponse {
+    "Route is OK"
+}
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let cors = CorsLayer::new()
+        .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+        .allow_methods(Any);
+
+    if origins.iter().any(|o| o == "*") {
+        cors.allow_origin(Any)
+    } else {
+        // Origins are validated when the config is loaded
+        cors.allow_origin(origins.iter()
+            .filter_map(|o| o.parse::<HeaderValue>().ok())
+            .collect::<Vec<_>>())
+    }
+}
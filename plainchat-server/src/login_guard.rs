@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+use crate::error::{AppError, Result};
+use crate::util::redis_store;
+use crate::util::sqlx_ext::Traced;
+use crate::AppState;
+
+const USER_AGENT_MAX_LEN: usize = 255;
+
+/// A login attempt, tracked by username so unknown accounts get delayed
+/// and locked exactly like existing ones.
+pub struct LoginAttempt {
+    username: String,
+    ip: Option<IpAddr>,
+    user_agent: Option<String>,
+}
+
+impl LoginAttempt {
+    pub fn new(username: &str, ip: Option<IpAddr>, user_agent: Option<&str>) -> Self {
+        Self {
+            username: username.to_lowercase(),
+            ip,
+            user_agent: user_agent.map(|ua| ua.chars().take(USER_AGENT_MAX_LEN).collect()),
+        }
+    }
+
+    fn account_key(&self) -> String {
+        format!("user:{}", self.username)
+    }
+
+    fn ip_key(&self) -> Option<String> {
+        self.ip.map(|ip| format!("ip:{ip}"))
+    }
+
+    /// Rejects the attempt while the account or ip is delayed or locked
+    /// out, before the password is even looked at. Lets everyone through
+    /// when redis is unavailable.
+    pub async fn check(&self, state: &AppState) -> Result<()> {
+        let config = &state.config.auth;
+        let mut subjects = vec![(self.account_key(), config.login_lockout_attempts)];
+        if let Some(ip_key) = self.ip_key() {
+            subjects.push((ip_key, config.login_ip_lockout_attempts));
+        }
+
+        let keys = subjects.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+        let mut redis = state.redis.clone();
+        let Ok(failures) = redis_store::login_failures(&mut redis, &keys).await else {
+            return Ok(());
+        };
+
+        let retry_after = failures.into_iter()
+            .zip(subjects)
+            .filter_map(|((count, since_last), (_, lockout_attempts))|
+                login_wait(config, count, since_last, lockout_attempts))
+            .max();
+
+        match retry_after {
+            Some(retry_after) => Err(AppError::RateLimited { retry_after }),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts the failure against the account and ip, and keeps an audit
+    /// record the user can see when the account exists.
+    pub async fn failed(&self, state: &AppState, user_id: Option<Uuid>) {
+        warn!(username = self.username, ip = ?self.ip, "Failed login");
+
+        let keys = std::iter::once(self.account_key())
+            .chain(self.ip_key())
+            .collect::<Vec<_>>();
+        let mut redis = state.redis.clone();
+        let _ = redis_store::add_login_failure(&mut redis, &keys, state.config.auth.login_lockout_secs).await;
+
+        let Some(user_id) = user_id else {
+            return;
+        };
+
+        let res = sqlx::query!(r#"
+            INSERT INTO failed_logins(user_id, ip, user_agent)
+            VALUES ($1, $2, $3)
+        "#, user_id, self.ip.map(|ip| ip.to_string()), self.user_agent)
+            .execute(&state.db)
+            .traced("failed")
+            .await;
+
+        if let Err(e) = res {
+            error!("Unable to record failed login: {e}");
+        }
+    }
+
+    /// Forgets the account's failures, the ip's keep counting as it may be
+    /// guessing other accounts.
+    pub async fn succeeded(&self, state: &AppState) {
+        let mut redis = state.redis.clone();
+        let _ = redis_store::clear_login_failures(&mut redis, &self.account_key()).await;
+    }
+}
+
+// How much longer a subject has to wait after `count` failures, the last
+// one `since_last` ago
+fn login_wait(config: &AuthConfig, count: u32, since_last: Duration, lockout_attempts: u32) -> Option<Duration> {
+    let lockout = Duration::from_secs(config.login_lockout_secs);
+    let delay = if count >= lockout_attempts {
+        lockout
+    } else if count >= config.login_free_attempts {
+        let doublings = (count - config.login_free_attempts).min(31);
+        Duration::from_secs(config.login_delay_secs)
+            .saturating_mul(1 << doublings)
+            .min(lockout)
+    } else {
+        return None;
+    };
+
+    delay.checked_sub(since_last).filter(|wait| !wait.is_zero())
+}
@@ -1,115 +1,54 @@
-use std::{env, process};
+use std::env;
+use std::sync::Arc;
 
-use axum::response::IntoResponse;
-use axum::{http::HeaderValue, response::Html};
-use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
-use axum::Router;
-use axum::routing::get;
 use dotenv::dotenv;
-use redis::{aio, AsyncCommands, Client, RedisConnectionInfo};
-use redis::aio::MultiplexedConnection;
-use socketioxide::layer::SocketIoLayer;
-use socketioxide::SocketIo;
-use sqlx::{PgPool, Pool, Postgres};
-use sqlx::postgres::PgPoolOptions;
+use plainchat_server::{config, seed, telemetry, AppState};
 use tokio::net::TcpListener;
-use tower::ServiceBuilder;
-use tower_http::cors::Any;
-use tower_http::{cors::CorsLayer, trace::{Trace, TraceLayer}};
-use tracing::{info, trace, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tokio::signal;
 
-mod error;
-mod routes;
-mod models;
-mod seed;
-mod ws;
-
-pub mod util;
-mod auth_extractor;
-
-#[derive(Clone, Debug)]
-struct AppState {
-    db: PgPool,
-    redis: MultiplexedConnection
-}
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
-    check_env();
-
+    let config = Arc::new(config::Config::load()?);
+    telemetry::init_logging(&config.telemetry)?;
 
-    let db = PgPoolOptions::new()
-        .max_connections(50)
-        .connect(&dotenv::var("DATABASE_URL")?).await?;
-
-    let redis_client = Client::open(dotenv::var("REDIS_URL")?)?;
-    let redis = redis_client.get_multiplexed_tokio_connection().await?;
-
-    sqlx::migrate!("./migrations")
-        .run(&db)
-        .await?;
+    let state = AppState::connect(config.clone()).await?;
 
     for arg in env::args() {
         if arg == "SEED_DB" {
             println!("Seeding Database...");
-            seed::populate_db(&db).await;
+            seed::populate_db(&state.db).await;
             println!("Done seeding!");
         }
     }
 
-    let state = AppState {db, redis};
-
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                "plainchat_server=trace,tower_http=debug,axum::rejection=debug".into()
-            })
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let metrics = telemetry::install_metrics()?;
+    let listener = TcpListener::bind(config.server.bind_addr).await?;
+    let internal_listener = TcpListener::bind(config.server.internal_addr).await?;
 
-    info!("TRACING INITIALIZED");
+    plainchat_server::serve(listener, internal_listener, state, metrics, shutdown_signal()).await?;
 
-    let app = Router::new()
-        .route("/", get(index_handler))
-        .nest("/api/user", routes::user::router())
-        .nest("/api/group", routes::group::router())
-        .layer(
-            ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(CorsLayer::new()
-                    .allow_origin(Any)
-                    .allow_headers([CONTENT_TYPE, AUTHORIZATION])
-                    .allow_methods(Any))
-                //.allow_origin(CorsLayer::permissive())//"http://0.0.0.0:3000".parse::<HeaderValue>().unwrap())
-                .layer(ws::layer(state.clone()))
-        )
-        .with_state(state);
-
-    let listener = TcpListener::bind("0.0.0.0:5000").await.unwrap();
-
-    axum::serve(listener, app).await.unwrap();
+    telemetry::shutdown();
 
     Ok(())
 }
 
-async fn index_handler() -> impl IntoResponse {
-    "Route is OK"
-}
-fn check_env() {
-
-    const REQUIRED_VARS: &[&'static str] = &[
-        "DATABASE_URL",
-        "JWT_SECRET",
-        "REDIS_URL"
-    ];
-
-    for v in REQUIRED_VARS.iter() {
-        if dotenv::var(v).is_err() {
-            println!("{v} must be set in .env");
-            std::process::exit(1);
-        }
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Ctrl+C handler should be installable");
+    };
+
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler should be installable")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
 }
-
@@ -16,34 +16,42 @@ pub enum MessageType {
     Event
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MessageModel {
-    pub id: Uuid,
-    pub sender_id: Option<i32>,
-    pub receiver_group_id: i32,
-    pub content: String,
-    pub msg_type: MessageType,
-    pub created_at: chrono::NaiveDateTime
-}
-
 #[derive(Debug,Serialize)]
 pub struct GroupModel {
     pub id: Uuid,
     pub name: String
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(type_name = "mention_policy", rename_all = "lowercase")]
+pub enum MentionPolicy {
+    Everyone,
+    Admins,
+    Nobody,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(type_name = "notification_level", rename_all = "lowercase")]
+pub enum NotificationLevel {
+    All,
+    Mentions,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(type_name = "export_status", rename_all = "lowercase")]
+pub enum ExportStatus {
+    Pending,
+    Ready,
+    Failed,
+}
+
 #[derive(Debug, Clone, sqlx::Type, Serialize, Deserialize)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
 pub enum UserRole {
     User,
     Admin,
 }
-#[derive(Debug, Serialize)]
-pub struct UserGroupModel {
-    pub user_id: Uuid,
-    pub group_id: Uuid,
-    pub role: UserRole
-}
 
 
 impl Display for UserRole {
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::http::Uri;
+use base64::Engine;
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tracing::{error, event, info, warn, Level};
+use uuid::Uuid;
+use web_push::{
+    ContentEncoding, HyperWebPushClient, PartialVapidSignatureBuilder, SubscriptionInfo,
+    VapidSignatureBuilder, WebPushClient, WebPushError, WebPushMessage, WebPushMessageBuilder,
+};
+
+use crate::config::PushConfig;
+use crate::models::NotificationLevel;
+use crate::util::redis_store::{self, RedisStore};
+use crate::util::sqlx_ext::Traced;
+
+const PUSH_QUEUE_SIZE: usize = 1024;
+const PUSH_TTL_SECS: u32 = 24 * 3600;
+const PUSH_MAX_ATTEMPTS: u32 = 4;
+const PUSH_RETRY_BASE: Duration = Duration::from_secs(2);
+// Subscriptions failing this many deliveries in a row are dropped
+const PUSH_MAX_FAILURES: i32 = 5;
+// Largest payload web-push encrypts, longer messages are cut short
+const PUSH_PAYLOAD_MAX: usize = 3052;
+
+/// A new message to fan out to offline members of its group.
+#[derive(Debug)]
+pub struct MessagePush {
+    pub group_id: Uuid,
+    pub sender_id: Uuid,
+    pub message_id: Uuid,
+    pub sender: String,
+    pub content: String,
+    pub date: chrono::NaiveDateTime,
+    pub mentioned: Vec<Uuid>,
+}
+
+#[derive(Serialize)]
+struct PushPayload<'a> {
+    message_id: Uuid,
+    group_id: Uuid,
+    group_name: &'a str,
+    sender: &'a str,
+    content: &'a str,
+    date: chrono::NaiveDateTime,
+    mentioned: bool,
+    /// Set when `content` is only the start of the message.
+    truncated: bool,
+}
+
+impl PushPayload<'_> {
+    // Drops the end of the content until the payload fits, on a char
+    // boundary. Escaping only grows it, so cutting the excess always helps.
+    fn into_bytes(mut self) -> serde_json::Result<Vec<u8>> {
+        loop {
+            let bytes = serde_json::to_vec(&self)?;
+            let excess = bytes.len().saturating_sub(PUSH_PAYLOAD_MAX);
+            if excess == 0 || self.content.is_empty() {
+                return Ok(bytes);
+            }
+
+            let mut end = self.content.len().saturating_sub(excess);
+            while !self.content.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.content = &self.content[..end];
+            self.truncated = true;
+        }
+    }
+}
+
+/// Handle to the push delivery worker, a no-op when VAPID keys are not configured.
+#[derive(Clone, Debug, Default)]
+pub struct PushQueue {
+    tx: Option<mpsc::Sender<MessagePush>>,
+    public_key: Option<String>,
+}
+
+impl PushQueue {
+    pub fn enqueue(&self, push: MessagePush) {
+        if let Some(tx) = &self.tx {
+            if let Err(e) = tx.try_send(push) {
+                warn!("Dropping push notification: {e}");
+            }
+        }
+    }
+
+    pub fn public_key(&self) -> Option<&str> {
+        self.public_key.as_deref()
+    }
+}
+
+struct VapidConfig {
+    key: PartialVapidSignatureBuilder,
+    subject: String,
+}
+
+impl VapidConfig {
+    fn new(config: &PushConfig) -> anyhow::Result<Option<Self>> {
+        let (Some(private_key), Some(subject)) = (&config.vapid_private_key, &config.vapid_subject) else {
+            return Ok(None);
+        };
+
+        let key = VapidSignatureBuilder::from_base64_no_sub(private_key, web_push::URL_SAFE_NO_PAD)
+            .map_err(|e| anyhow::anyhow!("push.vapid_private_key is invalid: {e}"))?;
+
+        Ok(Some(Self { key, subject: subject.clone() }))
+    }
+}
+
+/// Checks a subscription endpoint is an https url of a public host, so the
+/// worker can't be pointed at the server's own network. Returns why not.
+pub async fn check_endpoint(endpoint: &str, allow_local: bool) -> Result<(), String> {
+    let uri = endpoint.parse::<Uri>().map_err(|_| "must be a valid url".to_owned())?;
+    let port = match uri.scheme_str() {
+        Some("https") => 443,
+        Some("http") if allow_local => 80,
+        _ => return Err("must be an https url".to_owned()),
+    };
+    let host = uri.host()
+        .filter(|host| !host.is_empty())
+        .ok_or_else(|| "must have a host".to_owned())?;
+    if allow_local {
+        return Ok(());
+    }
+
+    // Names are resolved too, one may well point at a private address
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) if host.eq_ignore_ascii_case("localhost") || host.to_ascii_lowercase().ends_with(".localhost") =>
+            return Err("must not be a local host".to_owned()),
+        Err(_) => tokio::net::lookup_host((host, uri.port_u16().unwrap_or(port)))
+            .await
+            .map_err(|_| "must have a resolvable host".to_owned())?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    if addrs.is_empty() || !addrs.into_iter().all(is_public) {
+        return Err("must not point at a loopback, private or link-local address".to_owned());
+    }
+
+    Ok(())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified() ||
+        ip.is_loopback() ||
+        ip.is_private() ||
+        ip.is_link_local() ||
+        ip.is_broadcast() ||
+        ip.is_documentation() ||
+        ip.is_multicast() ||
+        a == 0 ||
+        // Carrier-grade NAT
+        (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified() ||
+        ip.is_loopback() ||
+        ip.is_multicast() ||
+        // Unique local and link-local
+        (first & 0xfe00) == 0xfc00 ||
+        (first & 0xffc0) == 0xfe80)
+}
+
+pub fn spawn_worker(db: PgPool, redis: RedisStore, config: PushConfig) -> anyhow::Result<PushQueue> {
+    let Some(vapid) = VapidConfig::new(&config)? else {
+        info!("VAPID keys are not set, push notifications are disabled");
+        return Ok(PushQueue::default());
+    };
+
+    let public_key = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(vapid.key.get_public_key());
+
+    let (tx, rx) = mpsc::channel(PUSH_QUEUE_SIZE);
+    let worker = PushWorker {
+        db,
+        redis,
+        allow_local: config.allow_local_endpoints,
+        vapid: Arc::new(vapid),
+        client: HyperWebPushClient::new(),
+    };
+    tokio::spawn(worker.run(rx));
+
+    Ok(PushQueue {
+        tx: Some(tx),
+        public_key: Some(public_key),
+    })
+}
+
+struct Subscription {
+    id: Uuid,
+    endpoint: String,
+    p256dh: String,
+    auth: String,
+}
+
+#[derive(Clone)]
+struct PushWorker<C> {
+    db: PgPool,
+    redis: RedisStore,
+    allow_local: bool,
+    vapid: Arc<VapidConfig>,
+    client: C,
+}
+
+impl<C> PushWorker<C>
+where
+    C: WebPushClient + Clone + Send + Sync + 'static,
+{
+    async fn run(mut self, mut rx: mpsc::Receiver<MessagePush>) {
+        while let Some(push) = rx.recv().await {
+            if let Err(e) = self.fan_out(push).await {
+                error!("Unable to fan out push notification: {e:?}");
+            }
+        }
+    }
+
+    async fn fan_out(&mut self, push: MessagePush) -> crate::error::Result<()> {
+        let recipients = sqlx::query!(r#"
+            SELECT
+                users.id AS user_id,
+                users.username,
+                groups.name AS group_name,
+                ug.notify AS "notify: NotificationLevel",
+                subs.id,
+                subs.endpoint,
+                subs.p256dh,
+                subs.auth
+            FROM user_groups AS ug
+            INNER JOIN users
+            ON users.id = ug.user_id
+            INNER JOIN groups
+            ON groups.id = ug.group_id
+            INNER JOIN push_subscriptions AS subs
+            ON subs.user_id = ug.user_id
+            WHERE
+                ug.group_id = $1 AND
+                ug.user_id <> $2 AND
+                ug.notify <> 'none' AND
+                (ug.muted_until IS NULL OR ug.muted_until <= now())
+        "#, push.group_id, push.sender_id)
+            .fetch_all(&self.db)
+            .traced("fan_out")
+            .await?;
+
+        let mut online = HashMap::new();
+        for r in recipients {
+            let mentioned = push.mentioned.contains(&r.user_id);
+            if r.notify == NotificationLevel::Mentions && !mentioned {
+                continue;
+            }
+
+            let is_online = match online.get(&r.user_id) {
+                Some(is_online) => *is_online,
+                None => {
+                    // Unknown presence pushes, better twice than never
+                    let is_online = redis_store::is_online(&mut self.redis, &r.username).await
+                        .unwrap_or(false);
+                    online.insert(r.user_id, is_online);
+                    is_online
+                }
+            };
+            if is_online {
+                continue;
+            }
+
+            let payload = PushPayload {
+                message_id: push.message_id,
+                group_id: push.group_id,
+                group_name: &r.group_name,
+                sender: &push.sender,
+                content: &push.content,
+                date: push.date,
+                mentioned,
+                truncated: false,
+            }
+                .into_bytes()
+                .context("Push payload should be serializable")?;
+
+            let sub = Subscription {
+                id: r.id,
+                endpoint: r.endpoint,
+                p256dh: r.p256dh,
+                auth: r.auth,
+            };
+            tokio::spawn(self.clone().deliver(sub, payload));
+        }
+
+        Ok(())
+    }
+
+    // Sends with exponential backoff on transient failures, dropping the
+    // subscription once the push service reports it gone or keeps rejecting it.
+    async fn deliver(self, sub: Subscription, payload: Vec<u8>) {
+        // Checked again as the endpoint's name may resolve elsewhere by now
+        if let Err(e) = check_endpoint(&sub.endpoint, self.allow_local).await {
+            warn!("Removing push subscription {} with a rejected endpoint: {e}", sub.id);
+            if let Err(e) = sqlx::query!("DELETE FROM push_subscriptions WHERE id = $1", sub.id)
+                .execute(&self.db)
+                .traced("deliver")
+                .await
+            {
+                error!("Unable to update push subscription {}: {e}", sub.id);
+            }
+            return;
+        }
+
+        for attempt in 1..=PUSH_MAX_ATTEMPTS {
+            // Failing to build the message is on us, not the subscription
+            let message = match self.message(&sub, &payload) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Unable to build push notification for subscription {}: {e}", sub.id);
+                    return;
+                }
+            };
+
+            let res = match self.client.send(message).await {
+                Ok(()) => sqlx::query!(r#"
+                    UPDATE push_subscriptions
+                    SET
+                        failures = 0,
+                        last_used_at = now()
+                    WHERE id = $1
+                "#, sub.id)
+                    .execute(&self.db)
+                    .traced("deliver")
+                    .await
+                    .map(|_| ()),
+                Err(WebPushError::EndpointNotValid | WebPushError::EndpointNotFound | WebPushError::InvalidUri) => {
+                    event!(Level::TRACE, "Removing expired push subscription {}", sub.id);
+                    sqlx::query!("DELETE FROM push_subscriptions WHERE id = $1", sub.id)
+                        .execute(&self.db)
+                        .traced("deliver")
+                        .await
+                        .map(|_| ())
+                },
+                Err(WebPushError::ServerError(retry_after)) if attempt < PUSH_MAX_ATTEMPTS => {
+                    tokio::time::sleep(retry_after.unwrap_or(PUSH_RETRY_BASE * 2u32.pow(attempt - 1))).await;
+                    continue;
+                },
+                Err(WebPushError::Unspecified) if attempt < PUSH_MAX_ATTEMPTS => {
+                    tokio::time::sleep(PUSH_RETRY_BASE * 2u32.pow(attempt - 1)).await;
+                    continue;
+                },
+                // Only answers of the push service count against the subscription
+                Err(e @ (
+                    WebPushError::Unauthorized |
+                    WebPushError::BadRequest(_) |
+                    WebPushError::PayloadTooLarge |
+                    WebPushError::ServerError(_) |
+                    WebPushError::InvalidResponse |
+                    WebPushError::Other(_)
+                )) => {
+                    warn!("Push delivery to subscription {} failed: {e}", sub.id);
+                    self.record_failure(sub.id).await
+                },
+                Err(e) => {
+                    warn!("Unable to reach the push service of subscription {}: {e}", sub.id);
+                    Ok(())
+                }
+            };
+
+            if let Err(e) = res {
+                error!("Unable to update push subscription {}: {e}", sub.id);
+            }
+            return;
+        }
+    }
+
+    async fn record_failure(&self, sub_id: Uuid) -> Result<(), sqlx::Error> {
+        let failures = sqlx::query!(r#"
+            UPDATE push_subscriptions
+            SET failures = failures + 1
+            WHERE id = $1
+            RETURNING failures
+        "#, sub_id)
+            .fetch_optional(&self.db)
+            .traced("record_failure")
+            .await?
+            .map(|rec| rec.failures);
+
+        if failures.is_some_and(|f| f >= PUSH_MAX_FAILURES) {
+            sqlx::query!("DELETE FROM push_subscriptions WHERE id = $1", sub_id)
+                .execute(&self.db)
+                .traced("record_failure")
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn message(&self, sub: &Subscription, payload: &[u8]) -> Result<WebPushMessage, WebPushError> {
+        let info = SubscriptionInfo::new(&sub.endpoint, &sub.p256dh, &sub.auth);
+
+        let mut signature = self.vapid.key.clone().add_sub_info(&info);
+        signature.add_claim("sub", self.vapid.subject.as_str());
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(PUSH_TTL_SECS);
+        builder.set_payload(ContentEncoding::Aes128Gcm, payload);
+        builder.set_vapid_signature(signature.build()?);
+
+        builder.build()
+    }
+}
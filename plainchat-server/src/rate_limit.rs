@@ -0,0 +1,155 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRef, FromRequestParts, MatchedPath, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde_json::json;
+use socketioxide::extract::SocketRef;
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::auth_extractor::AuthContext;
+use crate::config::{Limit, LimitKey};
+use crate::error::{AppError, Result};
+use crate::util::redis_store;
+use crate::AppState;
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Address of the client, the one the proxy forwarded for when trusted.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    AppState: FromRef<S>,
+    S: Send + Sync
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self> {
+        let state = AppState::from_ref(state);
+        let trust_forwarded_for = state.config.rate_limit.trust_forwarded_for;
+        Ok(ClientIp(client_ip(&parts.headers, &parts.extensions, trust_forwarded_for)))
+    }
+}
+
+/// Middleware limiting every route by its `rate_limit.routes` entry, meant
+/// as a route layer so the matched path is known.
+pub async fn limit_http(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next
+) -> Result<Response> {
+    let config = &state.config.rate_limit;
+    if !config.enabled {
+        return Ok(next.run(req).await);
+    }
+
+    let path = req.extensions()
+        .get::<MatchedPath>()
+        .map_or(req.uri().path(), MatchedPath::as_str);
+    let scope = format!("{} {path}", req.method());
+    let limit = config.route(req.method().as_str(), path);
+
+    let user_id = match limit.per {
+        LimitKey::User => bearer_user(req.headers(), &state),
+        LimitKey::Ip => None,
+    };
+    let ip = client_ip(req.headers(), req.extensions(), config.trust_forwarded_for);
+
+    if let Some(retry_after) = take(&state, &scope, user_id, ip, limit).await {
+        return Err(AppError::RateLimited { retry_after });
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Guard for socket handlers, failing once the user or ip has used up the
+/// event's bucket. The socket is also told with a `rate_limited` event, as
+/// not every client asks for acks.
+pub async fn limit_event(
+    state: &AppState,
+    s: &SocketRef,
+    user_id: Uuid,
+    event: &'static str
+) -> Result<()> {
+    let config = &state.config.rate_limit;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let limit = config.event(event);
+    let parts = s.req_parts();
+    let ip = client_ip(&parts.headers, &parts.extensions, config.trust_forwarded_for);
+    let user_id = (limit.per == LimitKey::User).then_some(user_id);
+
+    let Some(retry_after) = take(state, event, user_id, ip, limit).await else {
+        return Ok(());
+    };
+
+    let _ = s.emit("rate_limited", json!({
+        "event": event,
+        "retry_after_ms": retry_after.as_millis() as u64,
+    }));
+    Err(AppError::RateLimited { retry_after })
+}
+
+// Fails open, an unavailable redis shouldn't take the whole api down
+async fn take(
+    state: &AppState,
+    scope: &str,
+    user_id: Option<Uuid>,
+    ip: Option<IpAddr>,
+    limit: &Limit
+) -> Option<Duration> {
+    let subject = match (user_id, ip) {
+        (Some(user_id), _) => format!("user:{user_id}"),
+        (None, Some(ip)) => format!("ip:{ip}"),
+        (None, None) => return None,
+    };
+
+    let mut redis = state.redis.clone();
+    let retry_after = redis_store::take_token(
+        &mut redis,
+        scope,
+        &subject,
+        limit.requests_per_sec,
+        limit.burst)
+        .await
+        .ok()
+        .flatten()?;
+
+    event!(Level::DEBUG, "Rate limited {subject} on {scope} for {retry_after:?}");
+    metrics::counter!("rate_limited_total", "scope" => scope.to_owned()).increment(1);
+    Some(retry_after)
+}
+
+// Only the signature is checked, a revoked but unexpired token still
+// identifies who is sending the requests
+fn bearer_user(headers: &HeaderMap, state: &AppState) -> Option<Uuid> {
+    let token = headers.get(AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix(BEARER_PREFIX)?;
+
+    AuthContext::verify_jwt(token, &state.config.auth).ok()?
+        .claims.sub.parse().ok()
+}
+
+fn client_ip(headers: &HeaderMap, extensions: &Extensions, trust_forwarded_for: bool) -> Option<IpAddr> {
+    // The proxy appends the address it saw, anything before it is up to the client
+    let forwarded = trust_forwarded_for
+        .then(|| headers.get_all("x-forwarded-for").iter().next_back())
+        .flatten()
+        .and_then(|value| value.to_str().ok()?.rsplit(',').next()?.trim().parse().ok());
+
+    forwarded.or_else(|| extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip()))
+}
@@ -1,2 +1,4 @@
 pub mod user;
 pub mod group;
+pub mod group_archive;
+pub mod health;
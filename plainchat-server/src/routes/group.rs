@@ -1,26 +1,30 @@
-use std::{borrow::{Borrow, BorrowMut}, str::FromStr};
-
-use axum::{extract::{Request, State}, middleware::{self, Next}, response::Response, routing::{delete, post}, Json, Router};
-use axum::extract::Path;
+use axum::{extract::State, routing::delete, Router};
 use axum::routing::get;
-use redis::AsyncCommands;
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use sqlx::{PgPool, Row};
-use tokio::task::futures;
-use tower_http::follow_redirect::policy::PolicyExt;
-use tracing::{event, info, trace, warn, Level};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use sqlx::PgPool;
+use tracing::{event, info, trace, Level};
 use uuid::Uuid;
 
-use crate::{auth_extractor::AuthContext, models::MessageType, util::{redis_store, sqlx_ext::SqlxConstraints}, AppState};
+use crate::{auth_extractor::AuthContext, models::MessageType, util::{redis_store, sqlx_ext::{SqlxConstraints, Traced}}, AppState};
 use crate::error::{AppError, Result};
-use crate::models::{MessageModel, GroupModel, UserGroupModel, UserModel, UserRole};
+use crate::extract::{Json, Path, Query};
+use crate::models::{GroupModel, MentionPolicy, NotificationLevel, UserRole};
+use crate::validation::{FieldErrors, Validate, Validator};
+
+const MESSAGES_PAGE_MAX: i64 = 500;
+
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/", get(user_groups).post(create_group))
-        .route("/:group_id", delete(delete_group))
+        .route("/:group_id", delete(delete_group).patch(update_group))
         .route("/:group_id/members", get(list_members))
         .route("/:group_id/messages", get(list_messages))
+        .route("/:group_id/settings", get(notification_settings).patch(update_notification_settings))
+        .route("/:group_id/pins", get(list_pins))
+        .route("/:group_id/bans", get(list_bans))
+        .route("/:group_id/bans/:username", delete(lift_ban))
+        .merge(super::group_archive::router())
         //.route("/api/groups/:id/messages", get(list_group_messages))
 }
 
@@ -30,20 +34,24 @@ async fn create_group(
     AuthContext(user_id): AuthContext,
     Json(payload): Json<GroupPayload>
 ) -> Result<Json<Value>> {
-    // multiple query better with explicit acquire i guess idk
-    let mut conn = state.db.acquire().await?;
+    payload.validate(&state.validator)?;
+
+    // A group must never be left without its admin
+    let mut tx = state.db.begin().await?;
 
     let group_id = sqlx::query!(r#"
         INSERT INTO groups(name) 
         VALUES ($1) 
         RETURNING id
     "#, payload.name)
-       .fetch_one(conn.as_mut()).await?;
+       .fetch_one(&mut *tx).traced("create_group").await?;
 
     sqlx::query!(r#"
         INSERT INTO user_groups(user_id, group_id, role)
         VALUES ($1, $2, 'admin')
-    "#, user_id, group_id.id).execute(conn.as_mut()).await?;
+    "#, user_id, group_id.id).execute(&mut *tx).traced("create_group").await?;
+
+    tx.commit().await?;
     Ok(Json(Value::String(payload.name)))
 }
 
@@ -60,23 +68,42 @@ async fn user_groups(
     r#"
         SELECT 
             groups.id, 
-            name
+            name,
+            CASE
+                WHEN gs.notify = 'none' OR gs.muted_until > now() THEN 0
+                WHEN gs.notify = 'mentions' THEN (
+                    SELECT COUNT(*) 
+                    FROM mentions
+                    WHERE 
+                        mentions.user_id = $1 AND 
+                        mentions.group_id = gs.group_id AND
+                        mentions.read_at IS NULL
+                )
+                ELSE (
+                    SELECT COUNT(*)
+                    FROM messages
+                    WHERE 
+                        messages.receiver_group_id = gs.group_id AND
+                        messages.msg_type = 'normal' AND
+                        messages.created_at > gs.last_read_at AND
+                        messages.sender_id IS DISTINCT FROM $1
+                )
+            END AS "unread!"
         FROM (
             SELECT * FROM user_groups WHERE user_id = $1
         ) AS gs
         INNER JOIN groups
         ON gs.group_id = groups.id
-    "#, user_id).fetch_all(&state.db).await?;
+    "#, user_id).fetch_all(&state.db).traced("user_groups").await?;
 
     Ok(Json(groups))
 }
 async fn delete_group(
-    State(state): State<AppState>,
+    State(mut state): State<AppState>,
     AuthContext(user_id): AuthContext,
-    Path(group_id): Path<String>
+    Path(group_id): Path<Uuid>
 ) -> Result<Json<Value>> {
 
-    let group_id = Uuid::from_str(&group_id).expect("Id in path should be convertible to Uuid");
 
     if !user_in_group(
         &state.db, 
@@ -88,27 +115,153 @@ async fn delete_group(
         return Err(AppError::ForbiddenAction);
     }
 
-    let group = sqlx::query_as!(GroupModel, "SELECT * FROM groups WHERE id = $1", group_id)
+    let group = sqlx::query_as!(GroupModel, "SELECT id, name FROM groups WHERE id = $1", group_id)
             .fetch_one(&state.db)
+            .traced("delete_group")
             .await
             .map_non_existence_err("Group", "")?;
 
-    sqlx::query!("DELETE FROM user_groups WHERE group_id = $1", group_id).execute(&state.db).await?;
-    sqlx::query!("DELETE FROM messages WHERE receiver_group_id = $1", group_id).execute(&state.db).await?;
-    sqlx::query!("DELETE FROM groups WHERE id = $1", group_id).execute(&state.db).await?;
+    // Members, bans, messages, pins and mentions go with it
+    sqlx::query!("DELETE FROM groups WHERE id = $1", group_id).execute(&state.db).traced("delete_group").await?;
+
+    let _ = redis_store::invalidate_messages(&mut state.redis, &[group_id]).await;
  
     Ok(Json(Value::String(group.name)))
 }
 
+async fn update_group(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<GroupUpdatePayload>
+) -> Result<Json<GroupSettings>> {
+
+    if !user_in_group(
+        &state.db,
+        user_id,
+        group_id,
+        Some(UserRole::Admin))
+        .await?
+    {
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let settings = sqlx::query_as!(GroupSettings,
+    r#"
+        UPDATE groups
+        SET mention_policy = COALESCE($1, groups.mention_policy)
+        WHERE id = $2
+        RETURNING 
+            name,
+            mention_policy AS "mention_policy: MentionPolicy"
+    "#, payload.mention_policy as Option<MentionPolicy>, group_id)
+        .fetch_one(&state.db)
+        .traced("update_group")
+        .await
+        .map_non_existence_err("Group", "")?;
+
+    Ok(Json(settings))
+}
+
+async fn notification_settings(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(group_id): Path<Uuid>
+) -> Result<Json<NotificationSettings>> {
+
+    let settings = sqlx::query_as!(NotificationSettings,
+    r#"
+        SELECT
+            notify AS "notify: NotificationLevel",
+            muted_until
+        FROM user_groups
+        WHERE
+            user_id = $1 AND
+            group_id = $2
+    "#, user_id, group_id)
+        .fetch_optional(&state.db)
+        .traced("notification_settings")
+        .await?
+        .ok_or(AppError::ForbiddenAction)?;
+
+    Ok(Json(settings))
+}
+
+async fn update_notification_settings(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(group_id): Path<Uuid>,
+    Json(payload): Json<NotificationSettingsPayload>
+) -> Result<Json<NotificationSettings>> {
+
+    // An explicit null for muted_until unmutes, a missing field keeps it
+    let settings = sqlx::query_as!(NotificationSettings,
+    r#"
+        UPDATE user_groups
+        SET 
+            notify = COALESCE($1, user_groups.notify),
+            muted_until = CASE WHEN $2 THEN $3 ELSE user_groups.muted_until END
+        WHERE
+            user_id = $4 AND
+            group_id = $5
+        RETURNING
+            notify AS "notify: NotificationLevel",
+            muted_until
+    "#, 
+        payload.notify as Option<NotificationLevel>, 
+        payload.muted_until.is_some(),
+        payload.muted_until.flatten(),
+        user_id, 
+        group_id)
+        .fetch_optional(&state.db)
+        .traced("update_notification_settings")
+        .await?
+        .ok_or(AppError::ForbiddenAction)?;
+
+    Ok(Json(settings))
+}
+
+/// Marks everything in the group as read for the user, which resets its
+/// unread count and clears its pending mentions.
+pub async fn mark_group_read(
+    conn: &PgPool,
+    user_id: Uuid,
+    group_id: Uuid
+) -> Result<()> {
+    sqlx::query!(r#"
+        UPDATE user_groups
+        SET last_read_at = now()
+        WHERE
+            user_id = $1 AND
+            group_id = $2
+    "#, user_id, group_id)
+        .execute(conn)
+        .traced("mark_group_read")
+        .await?;
+
+    sqlx::query!(r#"
+        UPDATE mentions
+        SET read_at = now()
+        WHERE
+            user_id = $1 AND
+            group_id = $2 AND
+            read_at IS NULL
+    "#, user_id, group_id)
+        .execute(conn)
+        .traced("mark_group_read")
+        .await?;
+
+    Ok(())
+}
+
 async fn list_messages(
     State(mut state): State<AppState>,
     AuthContext(user_id): AuthContext,
-    Path(group_id): Path<String>
+    Path(group_id): Path<Uuid>,
+    Query(query): Query<MessageQuery>,
 ) -> Result<Json<Vec<Message>>> {
     event!(Level::TRACE, "LISTING MSGS!");
-
-    let group_id = Uuid::parse_str(&group_id)
-        .expect("Group ID in path should be convertible to Uuid");
+    query.validate(&state.validator)?;
 
     if !user_in_group(
         &state.db, 
@@ -120,56 +273,120 @@ async fn list_messages(
         return Err(AppError::ForbiddenAction);
     }
 
-    let cached_msgs = redis_store::get_messages(
-        &mut state.redis, 
-        group_id)
-        .await;
-
-    if !cached_msgs.is_empty() {
-        trace!("CACHED MSG ARE SENT!");
-        Ok(Json(cached_msgs))
-    } else {
-        let messages = sqlx::query_as!(Message, 
-        r#"
-            SELECT 
-                msgs.id, 
-                COALESCE(username, '') AS sender,
-                content, 
-                msgs.msg_type AS "msg_type: MessageType",
-                created_at AS date
-            FROM(
-                    SELECT *
-                    FROM messages 
-                    WHERE receiver_group_id = $1
-            ) AS msgs
-            LEFT JOIN users
-            ON users.id = msgs.sender_id
-            ORDER BY created_at
-        "#, group_id)
-            .fetch_all(&state.db)
-            .await?;
-
-        redis_store::cache_messages(
-            &mut state.redis, 
-            group_id, 
-            &messages)
-            .await?;
-        
-        Ok(Json(messages))
+    let window = match redis_store::get_messages(&mut state.redis, group_id).await {
+        Some(cached_msgs) => {
+            trace!("CACHED MSG ARE SENT!");
+            cached_msgs
+        },
+        None => {
+            let version = redis_store::messages_version(&mut state.redis, group_id).await;
+            let messages = fetch_messages(
+                &state.db, 
+                group_id, 
+                None, 
+                Some(state.config.redis.message_cache_size as i64))
+                .await?;
+
+            // Without redis the page is served from the db alone
+            if let Ok(version) = version {
+                let _ = redis_store::cache_messages(
+                    &mut state.redis, 
+                    group_id, 
+                    version,
+                    &messages)
+                    .await;
+            }
+
+            messages
+        }
+    };
+    // Older messages may only exist in the db once the window is full
+    let complete = window.len() < state.config.redis.message_cache_size;
+
+    let mut page = match query.before {
+        None => window,
+        Some(before) => match window.iter().position(|m| m.id == before) {
+            Some(idx) => {
+                let mut window = window;
+                window.truncate(idx);
+                window
+            },
+            // Past the cached window, the db has the whole page
+            None => return Ok(Json(
+                fetch_messages(&state.db, group_id, Some(before), query.limit).await?
+            )),
+        }
+    };
+
+    if let Some(limit) = query.limit {
+        let limit = limit as usize;
+        if page.len() >= limit {
+            return Ok(Json(page.split_off(page.len() - limit)));
+        }
     }
+    if complete {
+        return Ok(Json(page));
+    }
+
+    let oldest = page.first().map(|m| m.id).or(query.before);
+    let remaining = query.limit.map(|limit| limit - page.len() as i64);
+    let mut messages = fetch_messages(&state.db, group_id, oldest, remaining).await?;
+    messages.append(&mut page);
+
+    Ok(Json(messages))
+}
+
+// Newest `limit` messages of a group sent before the `before` message,
+// in chronological order.
+async fn fetch_messages(
+    db: &PgPool,
+    group_id: Uuid,
+    before: Option<Uuid>,
+    limit: Option<i64>,
+) -> Result<Vec<Message>> {
+    let mut messages = sqlx::query_as!(Message, 
+    r#"
+        SELECT 
+            msgs.id, 
+            users.username AS "sender?",
+            content, 
+            msgs.msg_type AS "msg_type: MessageType",
+            msgs.created_at AS date
+        FROM messages AS msgs
+        LEFT JOIN users
+        ON users.id = msgs.sender_id
+        WHERE 
+            msgs.receiver_group_id = $1 AND
+            (
+                $2::uuid IS NULL OR
+                (msgs.created_at, msgs.id) < (
+                    SELECT created_at, id
+                    FROM messages
+                    WHERE id = $2
+                )
+            )
+        ORDER BY msgs.created_at DESC, msgs.id DESC
+        LIMIT $3
+    "#, group_id, before, limit)
+        .fetch_all(db)
+        .traced("fetch_messages")
+        .await?;
+
+    messages.reverse();
+    Ok(messages)
 }
 
 async fn list_members(
     State(state): State<AppState>,
     AuthContext(user_id): AuthContext, 
-    Path(group_id): Path<String>
+    Path(group_id): Path<Uuid>
 ) -> Result<Json<Vec<Member>>> {
     event!(Level::INFO, "LIST GROUP INFO!");
 
     if !user_in_group(
         &state.db, 
         user_id, 
-        Uuid::parse_str(&group_id).unwrap(),
+        group_id,
         None)
         .await?
     {
@@ -190,17 +407,16 @@ async fn list_members(
         ON users.id = gs.user_id
         INNER JOIN groups
         ON groups.id = gs.group_id
-    "#, group_id.parse::<Uuid>()
-            .expect("Group ID should be convertible to Uuid"))
+    "#, group_id)
         .fetch_all(&state.db)
+    .traced("list_members")
     .await?;
 
 
 
     let mut redis_conn = state.redis.clone();
 
-    let mut mem_vec = vec![];
-    mem_vec.reserve(members.len());
+    let mut mem_vec = Vec::with_capacity(members.len());
 
     for m in members.into_iter() {
         let presense = redis_store::is_online(&mut redis_conn, &m.username).await;
@@ -217,6 +433,122 @@ async fn list_members(
 }
 
 
+async fn list_pins(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(group_id): Path<Uuid>
+) -> Result<Json<Vec<Pin>>> {
+
+    if !user_in_group(
+        &state.db,
+        user_id,
+        group_id,
+        None)
+        .await?
+    {
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let pins = sqlx::query_as!(Pin,
+    r#"
+        SELECT
+            msgs.id,
+            sender.username AS "sender?",
+            msgs.content,
+            msgs.created_at AS date,
+            pinner.username AS "pinned_by?",
+            pins.created_at AS pinned_at
+        FROM pinned_messages AS pins
+        INNER JOIN messages AS msgs
+        ON msgs.id = pins.message_id
+        LEFT JOIN users AS sender
+        ON sender.id = msgs.sender_id
+        LEFT JOIN users AS pinner
+        ON pinner.id = pins.pinned_by
+        WHERE pins.group_id = $1
+        ORDER BY pins.created_at DESC
+    "#, group_id)
+        .fetch_all(&state.db)
+        .traced("list_pins")
+        .await?;
+
+    Ok(Json(pins))
+}
+
+async fn list_bans(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(group_id): Path<Uuid>
+) -> Result<Json<Vec<Ban>>> {
+
+    if !user_in_group(
+        &state.db,
+        user_id,
+        group_id,
+        Some(UserRole::Admin))
+        .await?
+    {
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let bans = sqlx::query_as!(Ban,
+    r#"
+        SELECT
+            banned.username,
+            banner.username AS "banned_by?",
+            bans.reason,
+            bans.expires_at,
+            bans.created_at AS date
+        FROM group_bans AS bans
+        INNER JOIN users AS banned
+        ON banned.id = bans.user_id
+        LEFT JOIN users AS banner
+        ON banner.id = bans.banned_by
+        WHERE
+            bans.group_id = $1 AND
+            (bans.expires_at IS NULL OR bans.expires_at > now())
+        ORDER BY bans.created_at
+    "#, group_id)
+        .fetch_all(&state.db)
+        .traced("list_bans")
+        .await?;
+
+    Ok(Json(bans))
+}
+
+async fn lift_ban(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path((group_id, username)): Path<(Uuid, String)>
+) -> Result<Json<Value>> {
+
+    if !user_in_group(
+        &state.db,
+        user_id,
+        group_id,
+        Some(UserRole::Admin))
+        .await?
+    {
+        return Err(AppError::ForbiddenAction);
+    }
+
+    sqlx::query!(r#"
+        DELETE FROM group_bans
+        USING users
+        WHERE
+            users.id = group_bans.user_id AND
+            users.username = $1 AND
+            group_bans.group_id = $2
+        RETURNING group_bans.id
+    "#, username, group_id)
+        .fetch_one(&state.db)
+        .traced("lift_ban")
+        .await
+        .map_non_existence_err("Ban for", &username)?;
+
+    Ok(Json(Value::String(username)))
+}
+
 async fn user_exists(
     conn: &PgPool,
     user_id: Uuid
@@ -231,11 +563,12 @@ async fn user_exists(
             ) AS "exists!"
         "#, user_id)
             .fetch_one(conn)
+            .traced("user_exists")
             .await?
             .exists
     )
 }
-async fn user_in_group(
+pub async fn user_in_group(
     conn: &PgPool, 
     user_id: Uuid,
     group_id: Uuid,
@@ -251,8 +584,9 @@ async fn user_in_group(
                     user_id = $2 AND 
                     role = $3
             ) AS "exists!"
-        "#, group_id, user_id, r.to_string() as _)
+        "#, group_id, user_id, r as UserRole)
             .fetch_one(conn)
+            .traced("user_in_group")
             .await?
             .exists
     } else {
@@ -266,11 +600,34 @@ async fn user_in_group(
             ) AS "exists!"
         "#, group_id, user_id)
             .fetch_one(conn)
+            .traced("user_in_group")
             .await?
             .exists
     })
 
 
+}
+/// Whether the user currently has an active (non expired) ban in the group.
+pub async fn user_banned(
+    conn: &PgPool,
+    user_id: Uuid,
+    group_id: Uuid
+) -> Result<bool> {
+    Ok(sqlx::query!(
+    r#"
+        SELECT EXISTS (
+            SELECT 1 FROM group_bans
+            WHERE
+                group_id = $1 AND
+                user_id = $2 AND
+                (expires_at IS NULL OR expires_at > now())
+        ) AS "exists!"
+    "#, group_id, user_id)
+        .fetch_one(conn)
+        .traced("user_banned")
+        .await?
+        .exists
+    )
 }
 //async fn mw_require_group_member(
 //auth_ctx: AuthContext,
@@ -306,9 +663,61 @@ struct GroupPayload {
     name: String
 }
 
+impl Validate for GroupPayload {
+    fn validate(&self, validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        validator.group_name(&mut errors, "name", &self.name);
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct MessageQuery {
+    before: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+impl Validate for MessageQuery {
+    fn validate(&self, _validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        if self.limit.is_some_and(|limit| !(1..=MESSAGES_PAGE_MAX).contains(&limit)) {
+            errors.add("limit", format!("must be between 1 and {MESSAGES_PAGE_MAX}"));
+        }
+        errors.into_result()
+    }
+}
+
 #[derive(Deserialize)]
-struct GroupUserPayload {
-    username: String
+struct GroupUpdatePayload {
+    mention_policy: Option<MentionPolicy>,
+}
+
+#[derive(Serialize)]
+struct GroupSettings {
+    name: String,
+    mention_policy: MentionPolicy,
+}
+
+#[derive(Deserialize)]
+struct NotificationSettingsPayload {
+    notify: Option<NotificationLevel>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    muted_until: Option<Option<chrono::NaiveDateTime>>,
+}
+
+#[derive(Serialize)]
+struct NotificationSettings {
+    notify: NotificationLevel,
+    muted_until: Option<chrono::NaiveDateTime>,
+}
+
+// Lets `Option<Option<T>>` tell an explicit null apart from a missing field.
+fn deserialize_some<'de, T, D>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
 
 #[derive(Serialize, sqlx::Type, Deserialize, Debug, Clone)]
@@ -325,12 +734,32 @@ pub struct Message {
 struct Member {
     username: String,
     role: UserRole,
-    presence: bool,
+    // None while redis is unavailable
+    presence: Option<bool>,
+}
+#[derive(Serialize, Debug)]
+struct Pin {
+    id: Uuid,
+    sender: Option<String>,
+    content: String,
+    date: chrono::NaiveDateTime,
+    pinned_by: Option<String>,
+    pinned_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, Debug)]
+struct Ban {
+    username: String,
+    banned_by: Option<String>,
+    reason: Option<String>,
+    expires_at: Option<chrono::NaiveDateTime>,
+    date: chrono::NaiveDateTime,
 }
 #[derive(Serialize)]
 struct Group {
     id: Uuid,
     name: String,
+    unread: i64,
 }
 
 
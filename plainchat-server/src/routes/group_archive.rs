@@ -0,0 +1,322 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+use anyhow::Context;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth_extractor::AuthContext;
+use crate::error::{AppError, Result};
+use crate::extract::{Json, Path, Query};
+use crate::models::{MentionPolicy, MessageType, UserRole};
+use crate::routes::group::user_in_group;
+use crate::util::sqlx_ext::{SqlxConstraints, Traced};
+use crate::validation::{FieldErrors, Validate, Validator};
+use crate::AppState;
+
+const ARCHIVE_VERSION: u32 = 1;
+// Whole histories are larger than any other request body
+const IMPORT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/:group_id/export", get(export_group))
+        .route("/import", post(import_group).layer(DefaultBodyLimit::max(IMPORT_MAX_BYTES)))
+}
+
+async fn export_group(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(group_id): Path<Uuid>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response> {
+    if !user_in_group(&state.db, user_id, group_id, Some(UserRole::Admin)).await? {
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let group = sqlx::query_as!(ArchivedGroup, r#"
+        SELECT
+            name,
+            mention_policy AS "mention_policy: MentionPolicy"
+        FROM groups
+        WHERE id = $1
+    "#, group_id)
+        .fetch_one(&state.db)
+        .traced("export_group")
+        .await
+        .map_non_existence_err("Group", "")?;
+
+    let members = sqlx::query_as!(ArchivedMember, r#"
+        SELECT
+            users.username,
+            ug.role AS "role: UserRole",
+            ug.created_at AS joined_at
+        FROM user_groups AS ug
+        INNER JOIN users
+        ON users.id = ug.user_id
+        WHERE ug.group_id = $1
+        ORDER BY ug.created_at, users.username
+    "#, group_id)
+        .fetch_all(&state.db)
+        .traced("export_group")
+        .await?;
+
+    let messages = sqlx::query_as!(ArchivedMessage, r#"
+        SELECT
+            users.username AS "sender?",
+            msgs.content,
+            msgs.msg_type AS "msg_type: MessageType",
+            msgs.created_at AS date
+        FROM messages AS msgs
+        LEFT JOIN users
+        ON users.id = msgs.sender_id
+        WHERE msgs.receiver_group_id = $1
+        ORDER BY msgs.created_at, msgs.id
+    "#, group_id)
+        .fetch_all(&state.db)
+        .traced("export_group")
+        .await?;
+
+    let archive = GroupArchive {
+        version: ARCHIVE_VERSION,
+        exported_at: chrono::Utc::now().naive_utc(),
+        group,
+        members,
+        messages,
+    };
+
+    let (content_type, extension, body) = match query.format {
+        ArchiveFormat::Json => (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&archive)
+                .context("Group archive should be serializable")?,
+        ),
+        ArchiveFormat::Text => ("text/plain; charset=utf-8", "txt", transcript(&archive)),
+    };
+    let disposition = format!("attachment; filename=\"plainchat-group-{group_id}.{extension}\"");
+
+    Ok(([(CONTENT_TYPE, content_type.to_owned()), (CONTENT_DISPOSITION, disposition)], body).into_response())
+}
+
+fn transcript(archive: &GroupArchive) -> String {
+    let mut text = String::new();
+    let members = archive.members.iter()
+        .map(|m| match m.role {
+            UserRole::Admin => format!("{} (admin)", m.username),
+            UserRole::User => m.username.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let _ = writeln!(text, "# {}", archive.group.name);
+    let _ = writeln!(text, "# Exported {}", archive.exported_at.format("%Y-%m-%d %H:%M:%S"));
+    let _ = writeln!(text, "# Members: {}", members.join(", "));
+    let _ = writeln!(text);
+
+    for msg in &archive.messages {
+        let date = msg.date.format("%Y-%m-%d %H:%M:%S");
+        let _ = match (&msg.msg_type, &msg.sender) {
+            (MessageType::Event, _) => writeln!(text, "[{date}] * {}", msg.content),
+            (MessageType::Normal, Some(sender)) => writeln!(text, "[{date}] {sender}: {}", msg.content),
+            (MessageType::Normal, None) => writeln!(text, "[{date}] (deleted user): {}", msg.content),
+        };
+    }
+
+    text
+}
+
+async fn import_group(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Query(query): Query<ImportQuery>,
+    Json(archive): Json<GroupArchive>,
+) -> Result<Json<ImportSummary>> {
+    archive.validate(&state.validator)?;
+
+    // Members and senders are matched to the accounts of the same name
+    let names = archive.members.iter()
+        .map(|m| m.username.clone())
+        .chain(archive.messages.iter().filter_map(|msg| msg.sender.clone()))
+        .collect::<BTreeSet<_>>();
+    let accounts = sqlx::query!(
+        "SELECT id, username FROM users WHERE username = ANY($1)",
+        &names.iter().cloned().collect::<Vec<_>>()
+    )
+        .fetch_all(&state.db)
+        .traced("import_group")
+        .await?
+        .into_iter()
+        .map(|rec| (rec.username, rec.id))
+        .collect::<HashMap<_, _>>();
+    let unmatched_users = names.iter()
+        .filter(|name| !accounts.contains_key(*name))
+        .cloned()
+        .collect();
+
+    // Matched members only join with their archived roles when asked to,
+    // otherwise they are named so the importer can add them
+    let (members, invitees): (Vec<_>, Vec<_>) = archive.members.iter()
+        .filter_map(|m| Some((m, *accounts.get(&m.username)?)))
+        .filter(|(_, id)| *id != user_id)
+        .partition(|_| query.add_members);
+
+    let mut tx = state.db.begin().await?;
+
+    let group_id = sqlx::query!(r#"
+        INSERT INTO groups(name, mention_policy)
+        VALUES ($1, $2)
+        RETURNING id
+    "#, archive.group.name, archive.group.mention_policy as MentionPolicy)
+        .fetch_one(&mut *tx)
+        .traced("import_group")
+        .await?
+        .id;
+
+    // Whoever imports runs the group, whatever they were in the archive
+    sqlx::query!(r#"
+        INSERT INTO user_groups(user_id, group_id, role)
+        VALUES ($1, $2, 'admin')
+    "#, user_id, group_id)
+        .execute(&mut *tx)
+        .traced("import_group")
+        .await?;
+
+    let member_ids = members.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+    let roles = members.iter().map(|(m, _)| m.role.to_string()).collect::<Vec<_>>();
+    let joined_at = members.iter().map(|(m, _)| m.joined_at).collect::<Vec<_>>();
+    sqlx::query!(r#"
+        INSERT INTO user_groups(user_id, group_id, role, created_at)
+        SELECT m.user_id, $2, m.role::user_role, m.created_at
+        FROM UNNEST($1::uuid[], $3::text[], $4::timestamp[]) AS m(user_id, role, created_at)
+    "#, &member_ids, group_id, &roles, &joined_at)
+        .execute(&mut *tx)
+        .traced("import_group")
+        .await?;
+
+    // Messages keep their content as is, senders without an account here
+    // are unknown like those of deleted accounts
+    let mut sender_ids = Vec::with_capacity(archive.messages.len());
+    let mut contents = Vec::with_capacity(archive.messages.len());
+    let mut msg_types = Vec::with_capacity(archive.messages.len());
+    let mut dates = Vec::with_capacity(archive.messages.len());
+    for msg in archive.messages {
+        sender_ids.push(msg.sender.and_then(|name| accounts.get(&name).copied()));
+        contents.push(msg.content);
+        msg_types.push(msg.msg_type.to_string());
+        dates.push(msg.date);
+    }
+
+    let messages = sqlx::query!(r#"
+        INSERT INTO messages(sender_id, receiver_group_id, content, msg_type, created_at)
+        SELECT m.sender_id, $2, m.content, m.msg_type::message_type, m.created_at
+        FROM UNNEST($1::uuid[], $3::text[], $4::text[], $5::timestamp[]) AS m(sender_id, content, msg_type, created_at)
+    "#, &sender_ids as &[Option<Uuid>], group_id, &contents, &msg_types, &dates)
+        .execute(&mut *tx)
+        .traced("import_group")
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+
+    info!("Imported group {group_id} with {} members and {messages} messages", members.len() + 1);
+
+    Ok(Json(ImportSummary {
+        id: group_id,
+        name: archive.group.name,
+        messages,
+        members: members.into_iter().map(|(m, _)| m.username.clone()).collect(),
+        invitees: invitees.into_iter().map(|(m, _)| m.username.clone()).collect(),
+        unmatched_users,
+    }))
+}
+
+/// A group's whole history, as exported and imported. Senders and
+/// members are only known by name, ids don't carry over between
+/// deployments.
+#[derive(Serialize, Deserialize)]
+struct GroupArchive {
+    version: u32,
+    exported_at: NaiveDateTime,
+    group: ArchivedGroup,
+    members: Vec<ArchivedMember>,
+    messages: Vec<ArchivedMessage>,
+}
+
+impl Validate for GroupArchive {
+    fn validate(&self, validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        if self.version != ARCHIVE_VERSION {
+            errors.add("version", format!("must be {ARCHIVE_VERSION}"));
+        }
+        validator.group_name(&mut errors, "group.name", &self.group.name);
+        validator.import_size(&mut errors, self.members.len(), self.messages.len());
+        for (i, msg) in self.messages.iter().enumerate() {
+            validator.message(&mut errors, &format!("messages[{i}].content"), &msg.content);
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedGroup {
+    name: String,
+    mention_policy: MentionPolicy,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedMember {
+    username: String,
+    role: UserRole,
+    joined_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedMessage {
+    // None for events and deleted users
+    sender: Option<String>,
+    content: String,
+    msg_type: MessageType,
+    date: NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Adds archived members with an account here, with their roles.
+    #[serde(default)]
+    add_members: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ArchiveFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+#[derive(Serialize)]
+struct ImportSummary {
+    id: Uuid,
+    name: String,
+    messages: u64,
+    // Archived members added with their roles, besides the importer
+    members: Vec<String>,
+    // Archived members with an account here that weren't added, they can
+    // be added like anyone else
+    invitees: Vec<String>,
+    // Members and senders without an account here
+    unmatched_users: Vec<String>,
+}
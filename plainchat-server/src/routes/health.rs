@@ -0,0 +1,154 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::Serialize;
+use socketioxide::SocketIo;
+
+use crate::util::redis_store;
+use crate::util::sqlx_ext::Traced;
+use crate::AppState;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/live", get(live))
+        .route("/ready", get(ready))
+}
+
+// The process is up and serving requests, dependencies are not checked
+// so a db outage doesn't get the container restarted.
+async fn live() -> Json<Health> {
+    Json(Health {
+        status: Status::Up,
+        checks: None,
+    })
+}
+
+// Redis being down only degrades the server, everything it caches
+// falls back to postgres.
+async fn ready(
+    State(mut state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+) -> (StatusCode, Json<Health>) {
+    let db = timed(async {
+        sqlx::query("SELECT 1").execute(&state.db).traced("ready").await.map(|_| ())
+    }).await;
+
+    let redis = timed(redis_store::ping(&mut state.redis)).await;
+
+    let migrations = timed(check_migrations(&state.db)).await;
+
+    let sockets = match io.sockets() {
+        Ok(sockets) => Check::up(None).with_connections(sockets.len()),
+        Err(e) => Check::down(None, e.to_string()),
+    };
+
+    let status = if [&db, &migrations, &sockets].iter().any(|c| c.status == Status::Down) {
+        Status::Down
+    } else if redis.status == Status::Down {
+        Status::Degraded
+    } else {
+        Status::Up
+    };
+
+    let code = if status == Status::Down {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    (code, Json(Health {
+        status,
+        checks: Some(Checks { db, redis, migrations, sockets }),
+    }))
+}
+
+// Every migration embedded in the binary must have been applied
+async fn check_migrations(db: &sqlx::PgPool) -> anyhow::Result<()> {
+    let applied = sqlx::query_scalar::<_, i64>(
+        "SELECT version FROM _sqlx_migrations WHERE success ORDER BY version")
+        .fetch_all(db)
+        .traced("check_migrations")
+        .await?;
+
+    let pending = sqlx::migrate!("./migrations")
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .map(|m| m.version.to_string())
+        .collect::<Vec<_>>();
+
+    if !pending.is_empty() {
+        anyhow::bail!("pending migrations: {}", pending.join(", "));
+    }
+
+    Ok(())
+}
+
+async fn timed<F, E>(check: F) -> Check
+where
+    F: Future<Output = Result<(), E>>,
+    E: Into<anyhow::Error>,
+{
+    let start = Instant::now();
+    let res = tokio::time::timeout(CHECK_TIMEOUT, check).await;
+    let latency = Some(start.elapsed().as_secs_f64() * 1000.0);
+
+    match res {
+        Ok(Ok(())) => Check::up(latency),
+        Ok(Err(e)) => Check::down(latency, format!("{:#}", e.into())),
+        Err(_) => Check::down(latency, "timed out".to_owned()),
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Up,
+    Degraded,
+    Down,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checks: Option<Checks>,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    db: Check,
+    redis: Check,
+    migrations: Check,
+    sockets: Check,
+}
+
+#[derive(Serialize)]
+struct Check {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connections: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn up(latency_ms: Option<f64>) -> Self {
+        Self { status: Status::Up, latency_ms, connections: None, error: None }
+    }
+
+    fn down(latency_ms: Option<f64>, error: String) -> Self {
+        Self { status: Status::Down, latency_ms, connections: None, error: Some(error) }
+    }
+
+    fn with_connections(self, connections: usize) -> Self {
+        Self { connections: Some(connections), ..self }
+    }
+}
@@ -1,24 +1,48 @@
-use crate::auth_extractor::{AuthContext, TOKEN_EXPIRE_SECS};
+use crate::auth_extractor::AuthContext;
 use crate::util::redis_store;
 use crate::AppState;
 use axum::extract::State;
-use axum::routing::{delete, get, post, put};
-use axum::{Json, Router};
-use jsonwebtoken::{encode, Header};
-use redis::AsyncCommands;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, USER_AGENT};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Router};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use sqlx::{Acquire, Executor};
-use tracing::{event, info, Level};
+use serde_json::Value;
+use socketioxide::SocketIo;
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{event, warn, Level};
+use uuid::Uuid;
 
 use crate::error::{AppError, Result};
-use crate::models::UserModel;
-use crate::util::{sqlx_ext::SqlxConstraints, pass_hash};
+use crate::extract::{Json, Path, Query};
+use crate::login_guard::LoginAttempt;
+use crate::mail::Mail;
+use crate::models::{ExportStatus, UserModel};
+use crate::push;
+use crate::rate_limit::ClientIp;
+use crate::util::{sqlx_ext::{SqlxConstraints, Traced}, pass_hash};
+use crate::validation::{FieldErrors, Validate, Validator};
+use crate::ws;
+
+const MENTIONS_PAGE_SIZE: i64 = 50;
+const FAILED_LOGINS_PAGE_SIZE: i64 = 50;
+const EMAIL_TOKEN_EXPIRE_SECS: u64 = 24 * 3600;
 
 pub fn router() -> Router<AppState> {
     Router::new()
         .route("/auth", post(login_user))
         .route("/", post(create_user).put(update_user).delete(delete_user).get(curr_user))
+        .route("/mentions", get(list_mentions))
+        .route("/mentions/read", post(read_mentions))
+        .route("/push-subscriptions", post(subscribe_push).delete(unsubscribe_push))
+        .route("/push-subscriptions/key", get(push_public_key))
+        .route("/email", get(email_status).put(update_email).delete(remove_email))
+        .route("/email/verify", post(verify_email))
+        .route("/login-attempts", get(list_failed_logins))
+        .route("/export", post(request_export))
+        .route("/export/:id", get(download_export))
     //.route("/api/user", get(curr_user).put(update_user))
 }
 
@@ -28,6 +52,7 @@ async fn create_user(
 ) -> Result<Json<Value>> {
 
     event!(Level::TRACE, "Creating User...");
+    payload.validate(&state.validator)?;
 
     let password_hash = pass_hash::hash_password(payload.password).await?;
 
@@ -37,6 +62,7 @@ async fn create_user(
         password_hash
     )
     .execute(&state.db)
+    .traced("create_user")
     .await
     .map_unique_err("Username", &payload.username)?;
 
@@ -45,35 +71,46 @@ async fn create_user(
 
 async fn login_user(
     State(mut state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     Json(payload): Json<UserPayload>,
 ) -> Result<Json<User>> {
+    let user_agent = headers.get(USER_AGENT).and_then(|ua| ua.to_str().ok());
+    let attempt = LoginAttempt::new(&payload.username, ip, user_agent);
+    attempt.check(&state).await?;
+
     let user = sqlx::query_as!(
         UserModel,
         "SELECT id, username, password_hash FROM users WHERE username = $1",
         payload.username
     )
-    .fetch_one(&state.db)
-    .await
-    .map_non_existence_err("User", &payload.username)?;
+    .fetch_optional(&state.db)
+    .traced("login_user")
+    .await?;
 
-    pass_hash::verify_password(payload.password, user.password_hash).await?;
+    // Unknown users and wrong passwords are told apart by nothing, not
+    // even the time it takes to answer
+    let (user_id, password_hash) = user.map(|u| (u.id, u.password_hash)).unzip();
+    if let Err(e) = pass_hash::verify_password(payload.password, password_hash).await {
+        attempt.failed(&state, user_id).await;
+        return Err(e);
+    }
+    attempt.succeeded(&state).await;
 
-    let token = AuthContext(user.id).generate_jwt();
-    let key = format!("user-token:{}", token);
+    // Only a real user's password verifies
+    let user_id = user_id.ok_or(AppError::WrongCredentials(None))?;
 
-    // Expire redis key an hour before actual expire
-    // just to be sure.
-    state.redis.set_ex(
-        key, 
-        user.id.to_string(), 
-        TOKEN_EXPIRE_SECS.checked_sub(3600).unwrap())
-        .await?;
+    let token = AuthContext(user_id).generate_jwt(&state.config.auth);
 
-    redis_store::store_token(
+    // The token cache is only a shortcut, the JWT is verified without it
+    if let Err(e) = redis_store::store_token(
         &mut state.redis, 
         &token, 
-        user.id)
-        .await?;
+        user_id)
+        .await
+    {
+        warn!("Unable to cache token: {e}");
+    }
 
     Ok(Json(User {
         username: payload.username,
@@ -82,19 +119,18 @@ async fn login_user(
 }
 
 async fn update_user(
-    State(state): State<AppState>,
+    State(mut state): State<AppState>,
     AuthContext(user_id): AuthContext,
     Json(payload): Json<UserUpdatePayload>,
 ) -> Result<Json<User>> {
 
-    event!(Level::TRACE, "Updating user with {:?} and {:?}", 
-        payload.username, payload.password);
+    event!(Level::TRACE, "Updating user with {:?}", payload.username);
+    payload.validate(&state.validator)?;
 
-    let password_hash = if let Some(pass) = payload.password {
-        if pass.is_empty() {None} else {
-            pass_hash::hash_password(pass).await.ok()
-        }
-    } else { None };
+    let password_hash = match payload.password {
+        Some(pass) if !pass.is_empty() => Some(pass_hash::hash_password(pass).await?),
+        _ => None,
+    };
 
     let user = sqlx::query!(
         r#"
@@ -106,28 +142,169 @@ async fn update_user(
         "#, payload.username, password_hash, user_id
     )
         .fetch_one(&state.db)
-        .await?;
+        .traced("update_user")
+        .await
+        .map_unique_err("Username", payload.username.as_deref().unwrap_or_default())?;
+
+    // Cached messages carry the sender's old name
+    if payload.username.is_some() {
+        let groups = sender_groups(&state.db, user_id).await?;
+        let _ = redis_store::invalidate_messages(&mut state.redis, &groups).await;
+    }
 
     Ok(Json(User {
         username: user.username,
-        token: AuthContext(user_id).generate_jwt(),
+        token: AuthContext(user_id).generate_jwt(&state.config.auth),
     }))
 }
 
 async fn delete_user(
-    State(state): State<AppState>,
+    State(mut state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
     AuthContext(user_id): AuthContext,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<DeleteUserPayload>,
 ) -> Result<Json<Value>> {
-    sqlx::query!("DELETE FROM user_groups WHERE user_id = $1", user_id)
-        .execute(&state.db)
+    let user = sqlx::query_as!(
+        UserModel,
+        "SELECT id, username, password_hash FROM users WHERE id = $1",
+        user_id
+    )
+    .fetch_optional(&state.db)
+    .traced("delete_user")
+    .await?
+    .ok_or(AppError::InvalidToken)?;
+
+    // A stolen token must not be enough to guess the password here either
+    let user_agent = headers.get(USER_AGENT).and_then(|ua| ua.to_str().ok());
+    let attempt = LoginAttempt::new(&user.username, ip, user_agent);
+    attempt.check(&state).await?;
+    if let Err(e) = pass_hash::verify_password(payload.password, Some(user.password_hash)).await {
+        attempt.failed(&state, Some(user_id)).await;
+        return Err(e);
+    }
+
+    let mut stale_groups = sender_groups(&state.db, user_id).await?;
+
+    let mut tx = state.db.begin().await?;
+
+    // Groups that would be left without an admin
+    let sole_admin_groups = sqlx::query!(r#"
+        SELECT own.group_id
+        FROM user_groups AS own
+        WHERE
+            own.user_id = $1 AND
+            own.role = 'admin' AND
+            NOT EXISTS (
+                SELECT 1
+                FROM user_groups AS other
+                WHERE
+                    other.group_id = own.group_id AND
+                    other.role = 'admin' AND
+                    other.user_id <> $1
+            )
+        FOR UPDATE
+    "#, user_id)
+        .fetch_all(&mut *tx)
+        .traced("delete_user")
         .await?;
 
-    let user = sqlx::query!("DELETE FROM users WHERE id = $1 RETURNING username", user_id)
-        .fetch_one(&state.db)
+    let mut new_admins = vec![];
+    for group in sole_admin_groups {
+        // The longest standing member takes over, an empty group goes away
+        let successor = match payload.groups {
+            GroupDisposal::Transfer => sqlx::query_scalar!(r#"
+                UPDATE user_groups AS ug
+                SET role = 'admin'
+                FROM users
+                WHERE
+                    users.id = ug.user_id AND
+                    ug.id = (
+                        SELECT id
+                        FROM user_groups
+                        WHERE
+                            group_id = $1 AND
+                            user_id <> $2
+                        ORDER BY created_at, id
+                        LIMIT 1
+                    )
+                RETURNING users.username
+            "#, group.group_id, user_id)
+                .fetch_optional(&mut *tx)
+                .traced("delete_user")
+                .await?,
+            GroupDisposal::Delete => None,
+        };
+
+        match successor {
+            Some(successor) => {
+                let admin_msg = ws::insert_event(
+                    &mut tx,
+                    group.group_id,
+                    format!("{successor} is now the admin.")
+                ).await?;
+                new_admins.push((group.group_id, successor, admin_msg));
+            },
+            None => {
+                sqlx::query!("DELETE FROM groups WHERE id = $1", group.group_id)
+                    .execute(&mut *tx)
+                    .traced("delete_user")
+                    .await?;
+                stale_groups.push(group.group_id);
+            },
+        }
+    }
+
+    if payload.messages == MessageDisposal::Delete {
+        sqlx::query!("DELETE FROM messages WHERE sender_id = $1", user_id)
+            .execute(&mut *tx)
+            .traced("delete_user")
+            .await?;
+    }
+
+    // Memberships, bans, mentions, subscriptions and failed logins go with
+    // the account, messages that are kept lose their sender
+    sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+        .execute(&mut *tx)
+        .traced("delete_user")
+        .await?;
+
+    // Cached tokens are trusted without asking the db, so the account only
+    // goes once they are revoked
+    redis_store::revoke_tokens(&mut state.redis, user_id).await?;
+
+    tx.commit().await?;
+
+    let _ = redis_store::invalidate_messages(&mut state.redis, &stale_groups).await;
+    for (group_id, successor, admin_msg) in new_admins {
+        let _ = redis_store::append_message(&mut state.redis, group_id, admin_msg).await;
+        ws::announce_admin(&io, group_id, &successor);
+    }
+    redis_store::set_offline(&mut state.redis, &user.username).await;
+    // Someone registering the name again starts with a clean slate
+    attempt.succeeded(&state).await;
+
+    ws::disconnect_user(&io, user_id, "account_deleted");
+
+    Ok(Json(Value::String(user.username)))
+}
+
+// Groups holding messages sent by the user
+async fn sender_groups(db: &PgPool, user_id: Uuid) -> Result<Vec<Uuid>> {
+    let groups = sqlx::query!(r#"
+        SELECT DISTINCT receiver_group_id AS "id!"
+        FROM messages
+        WHERE sender_id = $1
+    "#, user_id)
+        .fetch_all(db)
+        .traced("sender_groups")
         .await?
-        .username;
+        .into_iter()
+        .map(|rec| rec.id)
+        .collect();
 
-    Ok(Json(Value::String(user)))
+    Ok(groups)
 }
 
 async fn curr_user(
@@ -140,12 +317,405 @@ async fn curr_user(
             user_id
         )
             .fetch_one(&state.db)
+            .traced("curr_user")
             .await
             .map_non_existence_err("User", "")?
             .username
     )))
 }
 
+async fn list_mentions(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Query(query): Query<MentionQuery>,
+) -> Result<Json<Vec<Mention>>> {
+    query.validate(&state.validator)?;
+
+    let mentions = sqlx::query_as!(Mention,
+    r#"
+        SELECT
+            mentions.id,
+            mentions.message_id,
+            mentions.group_id,
+            groups.name AS group_name,
+            sender.username AS "sender?",
+            msgs.content,
+            msgs.created_at AS date,
+            mentions.read_at IS NOT NULL AS "read!"
+        FROM mentions
+        INNER JOIN messages AS msgs
+        ON msgs.id = mentions.message_id
+        INNER JOIN groups
+        ON groups.id = mentions.group_id
+        LEFT JOIN users AS sender
+        ON sender.id = msgs.sender_id
+        WHERE
+            mentions.user_id = $1 AND
+            (NOT $2 OR mentions.read_at IS NULL)
+        ORDER BY mentions.created_at DESC
+        LIMIT $3
+    "#, user_id, query.unread.unwrap_or(false), query.limit.unwrap_or(MENTIONS_PAGE_SIZE))
+        .fetch_all(&state.db)
+        .traced("list_mentions")
+        .await?;
+
+    Ok(Json(mentions))
+}
+
+async fn read_mentions(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Json(payload): Json<MentionReadPayload>,
+) -> Result<Json<Value>> {
+    // Without explicit ids every unread mention is marked as read
+    let marked = sqlx::query!(
+    r#"
+        UPDATE mentions
+        SET read_at = now()
+        WHERE
+            user_id = $1 AND
+            read_at IS NULL AND
+            ($2::uuid[] IS NULL OR id = ANY($2))
+    "#, user_id, payload.ids.as_deref())
+        .execute(&state.db)
+        .traced("read_mentions")
+        .await?
+        .rows_affected();
+
+    Ok(Json(Value::from(marked)))
+}
+
+async fn list_failed_logins(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+) -> Result<Json<Vec<FailedLogin>>> {
+    let attempts = sqlx::query_as!(FailedLogin,
+    r#"
+        SELECT
+            id,
+            ip,
+            user_agent,
+            created_at AS date
+        FROM failed_logins
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        LIMIT $2
+    "#, user_id, FAILED_LOGINS_PAGE_SIZE)
+        .fetch_all(&state.db)
+        .traced("list_failed_logins")
+        .await?;
+
+    Ok(Json(attempts))
+}
+
+async fn request_export(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+) -> Result<(StatusCode, Json<DataExport>)> {
+    // Asking again while the archive is being built gets the same job
+    let pending = sqlx::query_as!(DataExport, r#"
+        SELECT
+            id,
+            status AS "status: ExportStatus",
+            created_at,
+            completed_at,
+            expires_at
+        FROM data_exports
+        WHERE
+            user_id = $1 AND
+            status = 'pending'
+    "#, user_id)
+        .fetch_optional(&state.db)
+        .traced("request_export")
+        .await?;
+    if let Some(pending) = pending {
+        return Ok((StatusCode::ACCEPTED, Json(pending)));
+    }
+
+    // Failed exports don't count against the cooldown
+    let wait = sqlx::query_scalar!(r#"
+        SELECT EXTRACT(EPOCH FROM
+            MAX(created_at) + $2 * interval '1 second' - now()
+        )::float8 AS wait
+        FROM data_exports
+        WHERE
+            user_id = $1 AND
+            status = 'ready'
+    "#, user_id, state.config.export.cooldown_secs as i64)
+        .fetch_one(&state.db)
+        .traced("request_export")
+        .await?;
+    if let Some(wait) = wait.filter(|wait| *wait > 0.0) {
+        return Err(AppError::RateLimited { retry_after: Duration::from_secs_f64(wait) });
+    }
+
+    let export = sqlx::query_as!(DataExport, r#"
+        INSERT INTO data_exports(user_id)
+        VALUES ($1)
+        RETURNING
+            id,
+            status AS "status: ExportStatus",
+            created_at,
+            completed_at,
+            expires_at
+    "#, user_id)
+        .fetch_one(&state.db)
+        .traced("request_export")
+        .await
+        .map_unique_err("Export", "pending")?;
+
+    state.exports.enqueue(export.id);
+    Ok((StatusCode::ACCEPTED, Json(export)))
+}
+
+async fn download_export(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Path(export_id): Path<Uuid>,
+) -> Result<Response> {
+    let export = sqlx::query!(r#"
+        SELECT
+            id,
+            status AS "status: ExportStatus",
+            archive,
+            created_at,
+            completed_at,
+            expires_at
+        FROM data_exports
+        WHERE
+            id = $1 AND
+            user_id = $2 AND
+            (expires_at IS NULL OR expires_at > now())
+    "#, export_id, user_id)
+        .fetch_optional(&state.db)
+        .traced("download_export")
+        .await?
+        .ok_or(AppError::DoesNotExist {
+            target_type: "Export".to_owned(),
+            data: export_id.to_string(),
+        })?;
+
+    match (export.status, export.archive) {
+        (ExportStatus::Ready, Some(archive)) => {
+            let disposition = format!(
+                "attachment; filename=\"plainchat-export-{}.json\"",
+                export.created_at.format("%Y-%m-%d"));
+            Ok((
+                [(CONTENT_TYPE, "application/json".to_owned()), (CONTENT_DISPOSITION, disposition)],
+                archive,
+            ).into_response())
+        },
+        (ExportStatus::Failed, _) => Err(AppError::Rejected {
+            status: StatusCode::GONE,
+            msg: "Export failed, request a new one".to_owned(),
+        }),
+        (status, _) => Ok((StatusCode::ACCEPTED, Json(DataExport {
+            id: export.id,
+            status,
+            created_at: export.created_at,
+            completed_at: export.completed_at,
+            expires_at: export.expires_at,
+        })).into_response()),
+    }
+}
+
+async fn push_public_key(
+    State(state): State<AppState>,
+) -> Result<Json<Value>> {
+    let key = state.push.public_key()
+        .ok_or(AppError::DoesNotExist {
+            target_type: "Push".to_owned(),
+            data: "key".to_owned()
+        })?;
+
+    Ok(Json(Value::String(key.to_owned())))
+}
+
+async fn subscribe_push(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Json(payload): Json<PushSubscriptionPayload>,
+) -> Result<Json<Value>> {
+    if let Err(msg) = push::check_endpoint(&payload.endpoint, state.config.push.allow_local_endpoints).await {
+        let mut errors = FieldErrors::default();
+        errors.add("endpoint", msg);
+        return Err(AppError::Validation(errors));
+    }
+
+    // Browsers reuse the endpoint on resubscribe, so it is moved to whoever
+    // registered it last
+    sqlx::query!(
+    r#"
+        INSERT INTO push_subscriptions(user_id, endpoint, p256dh, auth)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (endpoint) DO UPDATE
+        SET
+            user_id = EXCLUDED.user_id,
+            p256dh = EXCLUDED.p256dh,
+            auth = EXCLUDED.auth,
+            failures = 0
+    "#, user_id, payload.endpoint, payload.keys.p256dh, payload.keys.auth)
+        .execute(&state.db)
+        .traced("subscribe_push")
+        .await?;
+
+    Ok(Json(Value::String(payload.endpoint)))
+}
+
+async fn unsubscribe_push(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Json(payload): Json<PushUnsubscribePayload>,
+) -> Result<Json<Value>> {
+    sqlx::query!(
+    r#"
+        DELETE FROM push_subscriptions
+        WHERE
+            user_id = $1 AND
+            endpoint = $2
+        RETURNING id
+    "#, user_id, payload.endpoint)
+        .fetch_one(&state.db)
+        .traced("unsubscribe_push")
+        .await
+        .map_non_existence_err("Push subscription", "")?;
+
+    Ok(Json(Value::String(payload.endpoint)))
+}
+
+async fn email_status(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+) -> Result<Json<EmailStatus>> {
+    let status = sqlx::query_as!(EmailStatus,
+        r#"
+            SELECT
+                email,
+                email_verified_at IS NOT NULL AS "verified!",
+                pending_email
+            FROM users
+            WHERE id = $1
+        "#, user_id)
+        .fetch_one(&state.db)
+        .traced("email_status")
+        .await
+        .map_non_existence_err("User", "")?;
+
+    Ok(Json(status))
+}
+
+async fn update_email(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Json(payload): Json<EmailPayload>,
+) -> Result<Json<EmailStatus>> {
+    payload.validate(&state.validator)?;
+    let token = pass_hash::random_token();
+
+    let taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM users WHERE email = $1 AND id <> $2) AS "taken!""#,
+        payload.email, user_id)
+        .fetch_one(&state.db)
+        .traced("update_email")
+        .await?;
+    if taken {
+        return Err(AppError::AlreadyExists {
+            target_type: "Email".to_owned(),
+            data: payload.email,
+        });
+    }
+
+    // The address only replaces the current one once verified, so a token
+    // nobody received can't take it away
+    let status = sqlx::query_as!(EmailStatus,
+        r#"
+            UPDATE users
+            SET
+                pending_email = $1,
+                email_token = $2,
+                email_token_expires_at = now() + $3 * interval '1 second'
+            WHERE id = $4
+            RETURNING
+                email,
+                email_verified_at IS NOT NULL AS "verified!",
+                pending_email
+        "#, payload.email, token, EMAIL_TOKEN_EXPIRE_SECS as f64, user_id)
+        .fetch_one(&state.db)
+        .traced("update_email")
+        .await
+        .map_non_existence_err("User", "")?;
+
+    let app_url = &state.config.mail.app_url;
+    state.mailer.send(Mail {
+        to: payload.email,
+        subject: "Verify your Plainchat email".to_owned(),
+        body: format!(
+            "Confirm this address by opening the link below within a day, \
+            logged in to Plainchat:\n\n\
+            {app_url}/auth/verify-email?token={token}\n"),
+    }).await?;
+
+    Ok(Json(status))
+}
+
+async fn verify_email(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+    Json(payload): Json<EmailVerifyPayload>,
+) -> Result<Json<EmailStatus>> {
+    let status = sqlx::query_as!(EmailStatus,
+        r#"
+            UPDATE users
+            SET
+                email = pending_email,
+                email_verified_at = now(),
+                pending_email = NULL,
+                email_token = NULL,
+                email_token_expires_at = NULL
+            WHERE
+                id = $1 AND
+                pending_email IS NOT NULL AND
+                email_token = $2 AND
+                email_token_expires_at > now()
+            RETURNING
+                email,
+                email_verified_at IS NOT NULL AS "verified!",
+                pending_email
+        "#, user_id, payload.token)
+        .fetch_optional(&state.db)
+        .traced("verify_email")
+        .await
+        // Someone else may have verified the same address meanwhile
+        .map_unique_err("Email", "")?
+        .ok_or_else(|| AppError::DoesNotExist {
+            target_type: "Verification token".to_owned(),
+            data: "".to_owned(),
+        })?;
+
+    Ok(Json(status))
+}
+
+async fn remove_email(
+    State(state): State<AppState>,
+    AuthContext(user_id): AuthContext,
+) -> Result<Json<Value>> {
+    sqlx::query!(
+        r#"
+            UPDATE users
+            SET
+                email = NULL,
+                email_verified_at = NULL,
+                pending_email = NULL,
+                email_token = NULL,
+                email_token_expires_at = NULL
+            WHERE id = $1
+        "#, user_id)
+        .execute(&state.db)
+        .traced("remove_email")
+        .await?;
+
+    Ok(Json(Value::Null))
+}
 
 
 #[derive(Deserialize)]
@@ -160,6 +730,149 @@ struct UserUpdatePayload {
     password: Option<String>,
 }
 
+impl Validate for UserPayload {
+    fn validate(&self, validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        validator.username(&mut errors, "username", &self.username);
+        validator.password(&mut errors, "password", &self.password, Some(&self.username));
+        errors.into_result()
+    }
+}
+
+impl Validate for UserUpdatePayload {
+    fn validate(&self, validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        if let Some(username) = &self.username {
+            validator.username(&mut errors, "username", username);
+        }
+        // An empty password leaves it unchanged
+        if let Some(password) = self.password.as_deref().filter(|p| !p.is_empty()) {
+            validator.password(&mut errors, "password", password, self.username.as_deref());
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteUserPayload {
+    password: String,
+    #[serde(default)]
+    messages: MessageDisposal,
+    #[serde(default)]
+    groups: GroupDisposal,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MessageDisposal {
+    #[default]
+    Anonymize,
+    Delete,
+}
+
+// What happens to the groups the user is the only admin of
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum GroupDisposal {
+    #[default]
+    Transfer,
+    Delete,
+}
+
+#[derive(Deserialize)]
+struct MentionQuery {
+    unread: Option<bool>,
+    limit: Option<i64>,
+}
+
+impl Validate for MentionQuery {
+    fn validate(&self, _validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        if self.limit.is_some_and(|limit| !(1..=MENTIONS_PAGE_SIZE).contains(&limit)) {
+            errors.add("limit", format!("must be between 1 and {MENTIONS_PAGE_SIZE}"));
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct MentionReadPayload {
+    ids: Option<Vec<Uuid>>,
+}
+
+#[derive(Deserialize)]
+struct EmailPayload {
+    email: String,
+}
+
+impl Validate for EmailPayload {
+    fn validate(&self, validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        validator.email(&mut errors, "email", &self.email);
+        errors.into_result()
+    }
+}
+
+#[derive(Deserialize)]
+struct EmailVerifyPayload {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct EmailStatus {
+    email: Option<String>,
+    verified: bool,
+    // Address waiting for its verification
+    pending_email: Option<String>,
+}
+
+// Mirrors the browser's `PushSubscription.toJSON()` output
+#[derive(Deserialize)]
+struct PushSubscriptionPayload {
+    endpoint: String,
+    keys: PushSubscriptionKeys,
+}
+
+#[derive(Deserialize)]
+struct PushSubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+#[derive(Deserialize)]
+struct PushUnsubscribePayload {
+    endpoint: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Mention {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub group_id: Uuid,
+    pub group_name: String,
+    pub sender: Option<String>,
+    pub content: String,
+    pub date: chrono::NaiveDateTime,
+    pub read: bool,
+}
+
+#[derive(Serialize)]
+struct FailedLogin {
+    id: Uuid,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    date: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct DataExport {
+    id: Uuid,
+    status: ExportStatus,
+    created_at: chrono::NaiveDateTime,
+    completed_at: Option<chrono::NaiveDateTime>,
+    expires_at: Option<chrono::NaiveDateTime>,
+}
+
 #[derive(Serialize)]
 struct User {
     username: String,
@@ -0,0 +1,211 @@
+use std::time::Instant;
+
+use anyhow::Context;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::HeaderName;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tower_http::request_id::RequestId;
+use tracing::{info_span, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::config::{LogFormat, TelemetryConfig};
+use crate::AppState;
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+const DEFAULT_LOG_FILTER: &str = "plainchat_server=trace,tower_http=debug,axum::rejection=debug";
+
+/// Installs the global subscriber, in the configured log format.
+pub fn init_logging(config: &TelemetryConfig) -> anyhow::Result<()> {
+    let json = config.log_format == LogFormat::Json;
+    let filter = || EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| DEFAULT_LOG_FILTER.into());
+
+    let registry = tracing_subscriber::registry()
+        .with(json.then(|| tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_filter(filter())))
+        .with((!json).then(|| tracing_subscriber::fmt::layer()
+            .with_filter(filter())));
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::layer(config.otlp_endpoint.as_deref())?);
+
+    registry.init();
+    Ok(())
+}
+
+/// Flushes spans not exported yet.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+}
+
+/// Id of the request currently being handled, set by `scope_request_id`.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Makes the request id set by `SetRequestIdLayer` available to `request_id`,
+/// e.g. for error bodies.
+pub async fn scope_request_id(req: Request, next: Next) -> Response {
+    let id = req.extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+
+    REQUEST_ID.scope(id, next.run(req)).await
+}
+
+pub fn http_span(req: &Request) -> Span {
+    let request_id = req.extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+
+    let span = info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        request_id,
+    );
+
+    #[cfg(feature = "otel")]
+    otel::set_parent(&span, req.headers());
+
+    span
+}
+
+pub fn install_metrics() -> anyhow::Result<PrometheusHandle> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("duration_seconds".to_owned()), LATENCY_BUCKETS)?
+        .install_recorder()
+        .context("Unable to install metrics recorder")
+}
+
+pub fn metrics_router(handle: PrometheusHandle) -> Router<AppState> {
+    Router::new()
+        .route("/metrics", get(move |State(state): State<AppState>| async move {
+            // Pool gauges are sampled on scrape
+            metrics::gauge!("db_pool_connections").set(state.db.size() as f64);
+            metrics::gauge!("db_pool_idle_connections").set(state.db.num_idle() as f64);
+            metrics::gauge!("db_pool_max_connections")
+                .set(state.db.options().get_max_connections() as f64);
+
+            handle.render()
+        }))
+}
+
+/// Counts requests and their latency per matched route.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    // Unmatched paths share one label to keep cardinality bounded
+    let path = req.extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_owned(), |p| p.as_str().to_owned());
+    let method = req.method().to_string();
+
+    let res = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", res.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+
+    res.into_response()
+}
+
+pub fn socket_connected() {
+    metrics::gauge!("socket_connections").increment(1);
+}
+
+pub fn socket_disconnected() {
+    metrics::gauge!("socket_connections").decrement(1);
+}
+
+pub fn socket_event(event: &'static str) {
+    metrics::counter!("socket_events_total", "event" => event).increment(1);
+}
+
+// OTLP export, enabled at runtime by `telemetry.otlp_endpoint`. Every query
+// runs in a `db` span, with the statement logged by sqlx as its event.
+#[cfg(feature = "otel")]
+mod otel {
+    use std::sync::OnceLock;
+
+    use axum::http::HeaderMap;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_http::HeaderExtractor;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing::Span;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::{EnvFilter, Layer};
+
+    const OTEL_FILTER: &str = "plainchat_server=trace,tower_http=debug,sqlx::query=debug";
+
+    static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+    pub fn layer<S>(endpoint: Option<&str>) -> anyhow::Result<Option<impl Layer<S>>>
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(endpoint) = endpoint else {
+            return Ok(None);
+        };
+
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", "plainchat-server")]))
+            .build();
+        let tracer = provider.tracer("plainchat-server");
+        let _ = PROVIDER.set(provider);
+
+        Ok(Some(tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(EnvFilter::new(OTEL_FILTER))))
+    }
+
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            let _ = provider.shutdown();
+        }
+    }
+
+    // Continues the trace of the caller when it sent a traceparent header
+    pub fn set_parent(span: &Span, headers: &HeaderMap) {
+        let cx = opentelemetry::global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+        span.set_parent(cx);
+    }
+}
@@ -1,6 +1,8 @@
 pub mod pass_hash;
 pub mod sqlx_ext;
 pub mod redis_store;
+pub mod mentions;
+pub mod circuit_breaker;
 
 
 
@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A single probe call is in flight. Another one is let through if it
+    // hasn't been recorded by `until`, the first may have been dropped.
+    HalfOpen { until: Instant },
+}
+
+/// Stops calling a failing dependency after `threshold` failures in a row,
+/// letting a probe through every `cooldown` until it recovers.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            name,
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether a call may go through, must be followed by `record`.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } | BreakerState::HalfOpen { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen { until: Instant::now() + self.cooldown };
+                true
+            },
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = match (*state, success) {
+            (BreakerState::Closed { .. }, true) => BreakerState::Closed { failures: 0 },
+            (_, true) => {
+                info!("{} recovered, closing circuit", self.name);
+                BreakerState::Closed { failures: 0 }
+            },
+            (BreakerState::Closed { failures }, false) if failures + 1 < self.threshold =>
+                BreakerState::Closed { failures: failures + 1 },
+            (BreakerState::Open { until }, false) => BreakerState::Open { until },
+            (_, false) => {
+                warn!("{} is unavailable, opening circuit for {:?}", self.name, self.cooldown);
+                BreakerState::Open { until: Instant::now() + self.cooldown }
+            },
+        };
+    }
+
+    /// False while the circuit is open, i.e. the dependency is considered down.
+    pub fn available(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        !matches!(*state, BreakerState::Open { .. })
+    }
+}
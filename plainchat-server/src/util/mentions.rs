@@ -0,0 +1,51 @@
+use std::collections::BTreeSet;
+
+pub const MENTION_HERE: &str = "here";
+pub const MENTION_ALL: &str = "all";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Mentions {
+    pub usernames: BTreeSet<String>,
+    pub here: bool,
+    pub all: bool,
+}
+
+impl Mentions {
+    pub fn is_empty(&self) -> bool {
+        self.usernames.is_empty() && !self.here && !self.all
+    }
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+// Collects `@username`, `@here` and `@all` tokens from message content.
+// An `@` glued to a preceding word (e.g. emails) is not a mention.
+pub fn parse(content: &str) -> Mentions {
+    let mut mentions = Mentions::default();
+    let mut prev: Option<char> = None;
+
+    for (idx, c) in content.char_indices() {
+        let starts_mention = c == '@' && !prev.is_some_and(is_username_char);
+        prev = Some(c);
+
+        if !starts_mention {
+            continue;
+        }
+
+        let rest = &content[idx + 1..];
+        let end = rest.find(|c: char| !is_username_char(c)).unwrap_or(rest.len());
+        // Trailing dots are sentence punctuation rather than part of the name
+        let name = rest[..end].trim_end_matches('.');
+
+        match name {
+            "" => {},
+            MENTION_HERE => mentions.here = true,
+            MENTION_ALL => mentions.all = true,
+            _ => { mentions.usernames.insert(name.to_owned()); }
+        }
+    }
+
+    mentions
+}
@@ -1,25 +1,49 @@
+use std::sync::LazyLock;
+
 use anyhow::Context;
+use base64::Engine;
 
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
 use argon2::password_hash::SaltString;
 
 use crate::error::{AppError, Result};
 
 pub async fn hash_password(pass: String) -> Result<String> {
-    Ok(tokio::task::spawn_blocking(move || -> Result<String> {
+    tokio::task::spawn_blocking(move || -> Result<String> {
         let salt = SaltString::generate(&mut OsRng);
         let hashed = Argon2::default().hash_password(pass.as_bytes(), &salt)
             .map_err(anyhow::Error::msg)?.to_string();
         Ok(hashed)
-    }).await.context("panic while generating hash")??)
+    }).await.context("panic while generating hash")?
 }
 
-pub async fn verify_password(pass: String, hashed: String) -> Result<()> {
-    Ok(tokio::task::spawn_blocking(move || -> Result<()> {
+// Verified against for unknown users, so a login takes as long whether
+// the user exists or not
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(random_token().as_bytes(), &salt)
+        .expect("Hashing a random password should succeed")
+        .to_string()
+});
+
+/// Fails with the same error whether `hashed` is missing or doesn't match.
+pub async fn verify_password(pass: String, hashed: Option<String>) -> Result<()> {
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let exists = hashed.is_some();
+        let hashed = hashed.unwrap_or_else(|| DUMMY_HASH.clone());
         let parsed_hash = PasswordHash::new(&hashed)
             .map_err(anyhow::Error::msg)?;
         Argon2::default().verify_password(pass.as_bytes(), &parsed_hash)
-            .map_err(|_| AppError::WrongCredentials(Some("Password is incorrect".to_string())))
-    }).await.context("panic while verifying password")??)
-}
\ No newline at end of file
+            .ok()
+            .filter(|_| exists)
+            .ok_or(AppError::WrongCredentials(None))
+    }).await.context("panic while verifying password")?
+}
+
+/// Url safe random token for one time links, e.g. email verification.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -1,132 +1,566 @@
-use std::fmt::Display;
-use std::ops::Mul;
-use std::str::FromStr;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
-use anyhow::Context;
-use redis::{aio::MultiplexedConnection, AsyncCommands};
-use tracing::warn;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, ErrorKind, RedisError, RedisResult, Script};
+use tracing::{info_span, Instrument};
 use uuid::Uuid;
 
-use crate::auth_extractor::TOKEN_EXPIRE_SECS;
-use crate::error::{AppError, Result};
+use crate::config::Config;
+use crate::error::Result;
 use crate::routes::group::Message;
+use crate::util::circuit_breaker::CircuitBreaker;
 
-const REDIS_UTOKEN_KEY_BASE: &'static str = "user-token";
-const REDIS_USTATUS_KEY_BASE: &'static str = "user-presence";
-const REDIS_MSG_KEY_BASE:&'static str = "msgs";
+const REDIS_UTOKEN_KEY_BASE: &str = "user-token";
+const REDIS_UTOKENS_KEY_BASE: &str = "user-tokens";
+const REDIS_USTATUS_KEY_BASE: &str = "user-presence";
+const REDIS_MSG_KEY_BASE:&str = "msgs";
+const REDIS_MSG_BODY_KEY_BASE: &str = "msgs-body";
+const REDIS_MSG_VERSION_KEY_BASE: &str = "msgs-version";
+const REDIS_MSG_GENERATION_KEY: &str = "msgs-generation";
+const REDIS_RATE_KEY_BASE: &str = "rate";
+const REDIS_LOGIN_FAIL_KEY_BASE: &str = "login-fails";
 
-// 5 Days for msg cache 
-const REDIS_MSGS_EXPIRE: u64 = 3600 * 24 * 5;
+const REDIS_CALL_TIMEOUT: Duration = Duration::from_millis(500);
+const REDIS_BREAKER_THRESHOLD: u32 = 5;
+const REDIS_BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
 
 fn redis_token_key(token: &str) -> String {
     format!("{REDIS_UTOKEN_KEY_BASE}:{token}")
 }
+fn redis_user_tokens_key(user_id: Uuid) -> String {
+    format!("{REDIS_UTOKENS_KEY_BASE}:{user_id}")
+}
 fn redis_status_key(username: &str) -> String {
     format!("{REDIS_USTATUS_KEY_BASE}:{username}")
 }
 fn redis_msg_list_key(group_id: &str) -> String {
     format!("{REDIS_MSG_KEY_BASE}:{group_id}")
 }
+fn redis_msg_body_key(group_id: &str) -> String {
+    format!("{REDIS_MSG_BODY_KEY_BASE}:{group_id}")
+}
+fn redis_msg_version_key(group_id: &str) -> String {
+    format!("{REDIS_MSG_VERSION_KEY_BASE}:{group_id}")
+}
+fn redis_rate_key(scope: &str, subject: &str) -> String {
+    format!("{REDIS_RATE_KEY_BASE}:{scope}:{subject}")
+}
+fn redis_login_fail_key(subject: &str) -> String {
+    format!("{REDIS_LOGIN_FAIL_KEY_BASE}:{subject}")
+}
+
+/// Reconnecting redis connection behind a circuit breaker. While redis is
+/// down calls fail fast, so callers fall back to the JWT and postgres.
+#[derive(Clone)]
+pub struct RedisStore {
+    conn: ConnectionManager,
+    breaker: Arc<CircuitBreaker>,
+    token_ttl: u64,
+    msgs_ttl: u64,
+    // Only the newest messages of a group are cached
+    msgs_cache_size: usize,
+    // Set when a cache write was lost, until the shared generation is
+    // bumped so no instance trusts the caches written before
+    stale_msgs: Arc<AtomicBool>,
+}
+
+impl Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("breaker", &self.breaker)
+            .field("stale_msgs", &self.stale_msgs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    pub async fn connect(config: &Config) -> anyhow::Result<Self> {
+        let client = redis::Client::open(config.redis.url.as_str())?;
+        let conn = ConnectionManager::new(client).await?;
+
+        Ok(Self {
+            conn,
+            breaker: Arc::new(CircuitBreaker::new("Redis", REDIS_BREAKER_THRESHOLD, REDIS_BREAKER_COOLDOWN)),
+            token_ttl: config.token_cache_secs(),
+            msgs_ttl: config.redis.message_cache_ttl_secs,
+            msgs_cache_size: config.redis.message_cache_size,
+            stale_msgs: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn available(&self) -> bool {
+        self.breaker.available()
+    }
+
+    async fn run<T, F, Fut>(&self, op: &'static str, f: F) -> Result<T>
+    where
+        F: FnOnce(ConnectionManager) -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        if !self.breaker.allow() {
+            metrics::counter!("redis_commands_total", "op" => op, "result" => "rejected").increment(1);
+            return Err(RedisError::from((ErrorKind::IoError, "Redis circuit is open")).into());
+        }
+
+        let start = Instant::now();
+        let res = tokio::time::timeout(REDIS_CALL_TIMEOUT, f(self.conn.clone()))
+            .instrument(info_span!("redis", op))
+            .await
+            .unwrap_or_else(|_| Err(RedisError::from((ErrorKind::IoError, "Redis call timed out"))));
+        self.breaker.record(res.is_ok());
+
+        let result = if res.is_ok() { "ok" } else { "error" };
+        metrics::counter!("redis_commands_total", "op" => op, "result" => result).increment(1);
+        metrics::histogram!("redis_command_duration_seconds", "op" => op)
+            .record(start.elapsed().as_secs_f64());
+
+        Ok(res?)
+    }
+
+    // Retires every message cache after lost writes, in one write whoever
+    // reads them. Each cache carries the generation it was populated in.
+    async fn bump_msgs_generation(&self) -> Result<()> {
+        if !self.stale_msgs.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let res = self.run("bump_messages_generation", |mut conn| async move {
+            conn.incr::<_, _, ()>(REDIS_MSG_GENERATION_KEY, 1).await
+        }).await;
+        if res.is_err() {
+            self.stale_msgs.store(true, Ordering::Release);
+        }
+        res
+    }
+}
+
 
+pub async fn ping(conn: &mut RedisStore) -> Result<()> {
+    conn.run("ping", |mut conn| async move {
+        redis::cmd("PING").query_async::<()>(&mut conn).await
+    }).await
+}
 
 pub async fn store_token(
-    conn: &mut MultiplexedConnection, 
+    conn: &mut RedisStore, 
     token: &str, 
     user_id: Uuid
 ) -> Result<()> {
-    conn.set_ex(
-        redis_token_key(token),
-        user_id.to_string(),
-        TOKEN_EXPIRE_SECS.checked_sub(3600).unwrap())
-        .await?;
-    Ok(())
+    let key = redis_token_key(token);
+    let user_tokens = redis_user_tokens_key(user_id);
+    let ttl = conn.token_ttl;
+    // The user's tokens are indexed so they can all be revoked at once,
+    // the index lives as long as the newest token
+    conn.run("store_token", |mut conn| async move {
+        redis::pipe()
+            .atomic()
+            .set_ex(&key, user_id.to_string(), ttl).ignore()
+            .sadd(&user_tokens, &key).ignore()
+            .expire(&user_tokens, ttl as i64).ignore()
+            .query_async::<()>(&mut conn)
+            .await
+    }).await
+}
+
+/// Drops every cached token of the user.
+pub async fn revoke_tokens(
+    conn: &mut RedisStore,
+    user_id: Uuid
+) -> Result<()> {
+    let user_tokens = redis_user_tokens_key(user_id);
+    conn.run("revoke_tokens", |mut conn| async move {
+        let mut keys = conn.smembers::<_, Vec<String>>(&user_tokens).await?;
+        keys.push(user_tokens);
+        conn.del::<_, ()>(keys).await
+    }).await
 }
 
 pub async fn get_token(
-    conn: &mut MultiplexedConnection,
+    conn: &mut RedisStore,
     token: &str
 ) -> Option<Uuid> {
-    conn.get::<'_, _, Option<String>>(redis_token_key(token))
+    let key = redis_token_key(token);
+    conn.run("get_token", |mut conn| async move {
+        conn.get::<'_, _, Option<String>>(key).await
+    })
         .await
         .ok()
         .flatten()
-        .map(|ref id| Uuid::parse_str(id)
-            .expect("Redis Token ID should be convertible back to Uuid"))
+        .and_then(|id| Uuid::parse_str(&id).ok())
 }
 
 pub async fn set_online(
-    conn: &mut MultiplexedConnection,
+    conn: &mut RedisStore,
     username: &str,
 ) -> Result<()> {
-    conn.set(redis_status_key(username), "ON")
-        .await?;
-    Ok(())
+    let key = redis_status_key(username);
+    conn.run("set_online", |mut conn| async move {
+        conn.set::<_, _, ()>(key, "ON").await
+    }).await
 }
 
 pub async fn set_offline(
-    conn: &mut MultiplexedConnection,
+    conn: &mut RedisStore,
     username: &str
 ) {
-    let _ = conn.del::<'_, _, ()>
-        (redis_status_key(username)).await;
+    let key = redis_status_key(username);
+    let _ = conn.run("set_offline", |mut conn| async move {
+        conn.del::<'_, _, ()>(key).await
+    }).await;
 }
 
+/// None when presence is unknown because redis is unavailable.
 pub async fn is_online(
-    conn: &mut MultiplexedConnection, 
+    conn: &mut RedisStore, 
     username: &str
-) -> bool {
-    conn.get::<'_, _, String>(redis_status_key(username)).await
-        .is_ok()
+) -> Option<bool> {
+    let key = redis_status_key(username);
+    conn.run("is_online", |mut conn| async move {
+        conn.exists::<'_, _, bool>(key).await
+    })
+        .await
+        .ok()
 }
 
+/// Presence of each user, in order. None when redis is unavailable.
+pub async fn are_online(
+    conn: &mut RedisStore,
+    usernames: &[&str]
+) -> Option<Vec<bool>> {
+    if usernames.is_empty() {
+        return Some(vec![]);
+    }
+
+    let mut pipe = redis::pipe();
+    for username in usernames {
+        pipe.exists(redis_status_key(username));
+    }
+    conn.run("are_online", |mut conn| async move {
+        pipe.query_async::<Vec<bool>>(&mut conn).await
+    })
+        .await
+        .ok()
+}
+
+// The cache of a group is a sorted set of message ids scored by creation
+// time, with the messages themselves in a hash keyed by id. The hash also
+// holds the generation it was populated in under `gen`, so it exists for
+// groups without messages too, and is ignored once the generation moved on.
+// Every write bumps the group's version, populating only succeeds if no
+// write happened since the version was read before querying the db.
+static POPULATE_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    if (redis.call('GET', KEYS[3]) or '0') ~= ARGV[1] then
+        return 0
+    end
+    redis.call('DEL', KEYS[1], KEYS[2])
+    redis.call('HSET', KEYS[2], 'gen', redis.call('GET', KEYS[4]) or '0')
+    for i = 3, #ARGV, 3 do
+        redis.call('ZADD', KEYS[1], ARGV[i], ARGV[i + 1])
+        redis.call('HSET', KEYS[2], ARGV[i + 1], ARGV[i + 2])
+    end
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+    redis.call('EXPIRE', KEYS[2], ARGV[2])
+    return 1
+"));
+
+static APPEND_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    redis.call('INCR', KEYS[3])
+    redis.call('EXPIRE', KEYS[3], ARGV[5])
+    if redis.call('HGET', KEYS[2], 'gen') ~= (redis.call('GET', KEYS[4]) or '0') then
+        redis.call('DEL', KEYS[1], KEYS[2])
+        return 0
+    end
+    redis.call('ZADD', KEYS[1], ARGV[1], ARGV[2])
+    redis.call('HSET', KEYS[2], ARGV[2], ARGV[3])
+    local stale = redis.call('ZRANGE', KEYS[1], 0, -(tonumber(ARGV[4]) + 1))
+    if #stale > 0 then
+        redis.call('ZREM', KEYS[1], unpack(stale))
+        redis.call('HDEL', KEYS[2], unpack(stale))
+    end
+    redis.call('EXPIRE', KEYS[1], ARGV[5])
+    redis.call('EXPIRE', KEYS[2], ARGV[5])
+    return 1
+"));
+
+static READ_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    if redis.call('HGET', KEYS[2], 'gen') ~= (redis.call('GET', KEYS[3]) or '0') then
+        return false
+    end
+    local ids = redis.call('ZRANGE', KEYS[1], 0, -1)
+    if #ids == 0 then
+        return ids
+    end
+    return redis.call('HMGET', KEYS[2], unpack(ids))
+"));
+
+static INVALIDATE_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    redis.call('DEL', KEYS[1], KEYS[2])
+    redis.call('INCR', KEYS[3])
+    redis.call('EXPIRE', KEYS[3], ARGV[1])
+    return 1
+"));
+
+fn msg_score(msg: &Message) -> i64 {
+    msg.date.and_utc().timestamp_micros()
+}
+
+fn msg_keys(group_id: Uuid) -> [String; 4] {
+    let group_id = group_id.to_string();
+    [
+        redis_msg_list_key(&group_id),
+        redis_msg_body_key(&group_id),
+        redis_msg_version_key(&group_id),
+        REDIS_MSG_GENERATION_KEY.to_owned(),
+    ]
+}
+
+/// Version to pass to `cache_messages`, read before querying the db.
+pub async fn messages_version(
+    conn: &mut RedisStore,
+    group_id: Uuid
+) -> Result<u64> {
+    let key = redis_msg_version_key(&group_id.to_string());
+    let version = conn.run("messages_version", |mut conn| async move {
+        conn.get::<_, Option<u64>>(key).await
+    }).await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Replaces the cached window of a group with the newest of `msgs`, given
+/// in chronological order. Skipped if the group was written to since
+/// `version` was read, the next read populates it again.
 pub async fn cache_messages(
-    conn: &mut MultiplexedConnection, 
+    conn: &mut RedisStore, 
     group_id: Uuid, 
-    msgs: &Vec<Message>
+    version: u64,
+    msgs: &[Message]
 ) -> Result<()> {
-    let list = redis_msg_list_key(&group_id.to_string());
-    if conn.exists(&list).await? {
-        return Ok(());
-    }
-    for msg in msgs {
-        let json_str = serde_json::ser::to_string(msg).map_err(|e| anyhow::anyhow!(e))?;
-        conn.lpush(&list, json_str)
-            .await?;
-    }
+    conn.bump_msgs_generation().await?;
 
-    conn.expire(list, REDIS_MSGS_EXPIRE as i64)
-        .await?;
+    let [list, body, ver, gen] = msg_keys(group_id);
+    let skip = msgs.len().saturating_sub(conn.msgs_cache_size);
 
-    Ok(())
+    let mut invocation = POPULATE_SCRIPT.prepare_invoke();
+    invocation.key(list).key(body).key(ver).key(gen)
+        .arg(version)
+        .arg(conn.msgs_ttl);
+    for msg in &msgs[skip..] {
+        let msg_str = serde_json::ser::to_string(msg).map_err(|e| anyhow::anyhow!(e))?;
+        invocation.arg(msg_score(msg)).arg(msg.id.to_string()).arg(msg_str);
+    }
+
+    conn.run("cache_messages", |mut conn| async move {
+        invocation.invoke_async::<()>(&mut conn).await
+    }).await
 }
 
+/// Writes through to the group's cache if it is currently populated, so a
+/// lone message is never mistaken for the whole history. Appending a
+/// message already in the cache only replaces it.
 pub async fn append_message(
-    conn: &mut MultiplexedConnection,
+    conn: &mut RedisStore,
     group_id: Uuid,
     msg: Message
 ) -> Result<()> {
-    let list = redis_msg_list_key(&group_id.to_string());
+    // Other instances may still be reading caches this one lost writes to
+    let _ = conn.bump_msgs_generation().await;
+
+    let [list, body, ver, gen] = msg_keys(group_id);
     let msg_str = serde_json::ser::to_string(&msg).map_err(|e| anyhow::anyhow!(e))?;
-    conn.lpush(list, msg_str)
-        .await?;
+    let (size, ttl) = (conn.msgs_cache_size, conn.msgs_ttl);
+    let res = conn.run("append_message", |mut conn| async move {
+        APPEND_SCRIPT
+            .key(list).key(body).key(ver).key(gen)
+            .arg(msg_score(&msg))
+            .arg(msg.id.to_string())
+            .arg(msg_str)
+            .arg(size)
+            .arg(ttl)
+            .invoke_async::<()>(&mut conn)
+            .await
+    }).await;
+
+    if res.is_err() {
+        conn.stale_msgs.store(true, Ordering::Release);
+    }
+    res
+}
+
+pub async fn invalidate_messages(
+    conn: &mut RedisStore,
+    group_ids: &[Uuid]
+) -> Result<()> {
+    for group_id in group_ids {
+        let [list, body, ver, _] = msg_keys(*group_id);
+        let ttl = conn.msgs_ttl;
+        let res = conn.run("invalidate_messages", |mut conn| async move {
+            INVALIDATE_SCRIPT
+                .key(list).key(body).key(ver)
+                .arg(ttl)
+                .invoke_async::<()>(&mut conn)
+                .await
+        }).await;
+
+        if res.is_err() {
+            conn.stale_msgs.store(true, Ordering::Release);
+            return res;
+        }
+    }
 
     Ok(())
 }
+
+/// Cached window of a group in chronological order, None on a cache miss.
+/// A window shorter than the configured cache size holds the whole history,
+/// an empty one means the group has no messages.
 pub async fn get_messages(
-    conn: &mut MultiplexedConnection,
+    conn: &mut RedisStore,
     group_id: Uuid
-) -> Vec<Message> {
-    let list = redis_msg_list_key(&group_id.to_string());
-    conn.lrange::<'_, _, Vec<String>>(list, 0, -1)
+) -> Option<Vec<Message>> {
+    let msgs = read_messages(conn, group_id).await;
+
+    let result = if msgs.is_some() { "hit" } else { "miss" };
+    metrics::counter!("message_cache_lookups_total", "result" => result).increment(1);
+
+    msgs
+}
+
+async fn read_messages(
+    conn: &mut RedisStore,
+    group_id: Uuid
+) -> Option<Vec<Message>> {
+    conn.bump_msgs_generation().await.ok()?;
+
+    let [list, body, _, gen] = msg_keys(group_id);
+    let msgs_str = conn.run("get_messages", |mut conn| async move {
+        READ_SCRIPT
+            .key(list).key(body).key(gen)
+            .invoke_async::<Option<Vec<Option<String>>>>(&mut conn)
+            .await
+    })
         .await
-        //.map_err(|e| AppError::Redis(e))
-        .map(|msgs_str| 
-            msgs_str.iter()
-                .rev()
-                .map(String::as_str)
-                .map(serde_json::de::from_str::<'_, Message>)
-                .map(|res| res.map_err(|e| anyhow::anyhow!(e)).unwrap())
-                .collect::<Vec<Message>>()
-        ).unwrap_or(vec![])
+        .ok()??;
+
+    msgs_str.iter()
+        .map(|msg| serde_json::de::from_str::<'_, Message>(msg.as_deref()?).ok())
+        .collect::<Option<Vec<Message>>>()
+}
+
+// A bucket is a hash of the tokens left and when they were counted, refilled
+// lazily on every take. Redis' clock is used so every server instance agrees.
+// Floats are returned as strings, redis truncates lua numbers to integers.
+static TAKE_TOKEN_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    local rate = tonumber(ARGV[1])
+    local burst = tonumber(ARGV[2])
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+    local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+    local tokens = tonumber(bucket[1]) or burst
+    local at = tonumber(bucket[2]) or now
+    tokens = math.min(burst, tokens + math.max(0, now - at) * rate)
+    local wait = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+    else
+        wait = (1 - tokens) / rate
+    end
+    redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+    redis.call('EXPIRE', KEYS[1], math.ceil(burst / rate) + 1)
+    return tostring(wait)
+"));
+
+/// Takes a token from the bucket of `subject` in `scope`, returning how
+/// long to wait for the next one when the bucket is empty.
+pub async fn take_token(
+    conn: &mut RedisStore,
+    scope: &str,
+    subject: &str,
+    requests_per_sec: f64,
+    burst: u32
+) -> Result<Option<Duration>> {
+    let key = redis_rate_key(scope, subject);
+    let wait = conn.run("take_token", |mut conn| async move {
+        TAKE_TOKEN_SCRIPT
+            .key(key)
+            .arg(requests_per_sec)
+            .arg(burst)
+            .invoke_async::<String>(&mut conn)
+            .await
+    }).await?;
+
+    let wait = wait.parse::<f64>().map_err(|e| anyhow::anyhow!(e))?;
+    Ok((wait > 0.0).then(|| Duration::from_secs_f64(wait)))
+}
+
+// Failed logins are a hash of their count and the time of the last one in
+// milliseconds, forgotten once the window passes without another failure.
+static LOGIN_FAILURES_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    local res = {}
+    for i, key in ipairs(KEYS) do
+        local failures = redis.call('HMGET', key, 'count', 'last')
+        res[i] = {tonumber(failures[1]) or 0, now - (tonumber(failures[2]) or now)}
+    end
+    return res
+"));
+
+static ADD_LOGIN_FAILURE_SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(r"
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    for _, key in ipairs(KEYS) do
+        redis.call('HINCRBY', key, 'count', 1)
+        redis.call('HSET', key, 'last', now)
+        redis.call('EXPIRE', key, ARGV[1])
+    end
+    return 1
+"));
 
+/// Failed logins of each subject, with how long ago the last one was.
+pub async fn login_failures(
+    conn: &mut RedisStore,
+    subjects: &[String]
+) -> Result<Vec<(u32, Duration)>> {
+    let mut invocation = LOGIN_FAILURES_SCRIPT.prepare_invoke();
+    for subject in subjects {
+        invocation.key(redis_login_fail_key(subject));
+    }
+
+    let failures = conn.run("login_failures", |mut conn| async move {
+        invocation.invoke_async::<Vec<(u32, u64)>>(&mut conn).await
+    }).await?;
+
+    Ok(failures.into_iter()
+        .map(|(count, since_ms)| (count, Duration::from_millis(since_ms)))
+        .collect())
+}
+
+pub async fn add_login_failure(
+    conn: &mut RedisStore,
+    subjects: &[String],
+    window_secs: u64
+) -> Result<()> {
+    let mut invocation = ADD_LOGIN_FAILURE_SCRIPT.prepare_invoke();
+    for subject in subjects {
+        invocation.key(redis_login_fail_key(subject));
+    }
+    invocation.arg(window_secs);
+
+    conn.run("add_login_failure", |mut conn| async move {
+        invocation.invoke_async::<()>(&mut conn).await
+    }).await
+}
+
+pub async fn clear_login_failures(
+    conn: &mut RedisStore,
+    subject: &str
+) -> Result<()> {
+    let key = redis_login_fail_key(subject);
+    conn.run("clear_login_failures", |mut conn| async move {
+        conn.del::<_, ()>(key).await
+    }).await
 }
@@ -1,5 +1,9 @@
-use crate::error::AppError;
+use std::future::Future;
+
+use tracing::instrument::Instrumented;
+use tracing::{info_span, Instrument};
 
+use crate::error::AppError;
 
 pub trait SqlxConstraints<T> {
     fn map_unique_err(self, target_type: &str, data: &str) -> crate::error::Result<T>;
@@ -9,14 +13,15 @@ pub trait SqlxConstraints<T> {
 impl<T> SqlxConstraints<T> for std::result::Result<T, sqlx::Error> {
     fn map_unique_err(self, target_type: &str, data: &str) -> crate::error::Result<T> {
         self.map_err(|e| 
-                e.as_database_error()
-                    .is_some_and(|dbe| dbe.is_unique_violation())
-                    .then(|| AppError::AlreadyExists { 
-                        target_type: target_type.to_owned(), 
-                        data: data.to_owned()
-                    })
-                    .unwrap_or(AppError::Sqlx(e))
-            )
+            if e.as_database_error().is_some_and(|dbe| dbe.is_unique_violation()) {
+                AppError::AlreadyExists { 
+                    target_type: target_type.to_owned(), 
+                    data: data.to_owned()
+                }
+            } else {
+                AppError::Sqlx(e)
+            }
+        )
     }
 
     fn map_non_existence_err(self, target_type: &str, data: &str) -> crate::error::Result<T> {
@@ -32,3 +37,14 @@ impl<T> SqlxConstraints<T> for std::result::Result<T, sqlx::Error> {
         )
     }
 }
+
+/// Runs a query in its own `db` span, so db calls show up in traces next
+/// to redis ones, with the statement logged by sqlx as their event. `op`
+/// names the caller.
+pub trait Traced: Future + Sized {
+    fn traced(self, op: &'static str) -> Instrumented<Self> {
+        self.instrument(info_span!("db", op))
+    }
+}
+
+impl<F: Future> Traced for F {}
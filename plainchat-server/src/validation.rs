@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::info;
+
+use crate::config::{NameRules, PasswordRules, ValidationConfig};
+use crate::error::{AppError, Result};
+
+const EMAIL_MAX_LEN: usize = 255;
+
+/// Messages by field, returned to the client all at once.
+#[derive(Debug, Default, Serialize)]
+pub struct FieldErrors(BTreeMap<String, Vec<String>>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, msg: impl Into<String>) {
+        self.0.entry(field.to_owned()).or_default().push(msg.into());
+    }
+
+    pub fn into_result(self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(self))
+        }
+    }
+}
+
+/// Payloads checked against the configured rules before touching the db.
+pub trait Validate {
+    fn validate(&self, validator: &Validator) -> Result<()>;
+}
+
+pub struct Validator {
+    config: ValidationConfig,
+    // Lowercased, a breached password is just as guessable in another case
+    breached: HashSet<String>,
+}
+
+impl Debug for Validator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Validator")
+            .field("config", &self.config)
+            .field("breached", &self.breached.len())
+            .finish()
+    }
+}
+
+impl Validator {
+    pub fn new(config: &ValidationConfig) -> anyhow::Result<Self> {
+        let breached = match &config.password.breached_list {
+            Some(path) => {
+                let list = std::fs::read(path)
+                    .with_context(|| format!("Unable to read breached password list {path}"))?;
+                let breached = list.split(|b| *b == b'\n')
+                    .map(|line| String::from_utf8_lossy(line).trim_end_matches('\r').to_lowercase())
+                    .filter(|line| !line.is_empty())
+                    .collect::<HashSet<_>>();
+                info!("Loaded {} breached passwords", breached.len());
+                breached
+            },
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            breached,
+        })
+    }
+
+    pub fn username(&self, errors: &mut FieldErrors, field: &str, username: &str) {
+        check_name(&self.config.username, errors, field, username);
+    }
+
+    pub fn group_name(&self, errors: &mut FieldErrors, field: &str, name: &str) {
+        check_name(&self.config.group_name, errors, field, name);
+    }
+
+    pub fn email(&self, errors: &mut FieldErrors, field: &str, email: &str) {
+        if email.len() > EMAIL_MAX_LEN {
+            errors.add(field, format!("must be at most {EMAIL_MAX_LEN} characters"));
+        }
+        // Whatever the relay would refuse as a recipient
+        if email.parse::<lettre::Address>().is_err() {
+            errors.add(field, "must be a valid email address");
+        }
+    }
+
+    pub fn message(&self, errors: &mut FieldErrors, field: &str, content: &str) {
+        let max_len = self.config.message.max_len;
+        if content.chars().count() > max_len {
+            errors.add(field, format!("must be at most {max_len} characters"));
+        }
+    }
+
+    pub fn import_size(&self, errors: &mut FieldErrors, members: usize, messages: usize) {
+        let rules = &self.config.import;
+        if members > rules.max_members {
+            errors.add("members", format!("must be at most {}", rules.max_members));
+        }
+        if messages > rules.max_messages {
+            errors.add("messages", format!("must be at most {}", rules.max_messages));
+        }
+    }
+
+    /// `username` is the one the password will belong to, when known.
+    pub fn password(&self, errors: &mut FieldErrors, field: &str, password: &str, username: Option<&str>) {
+        let rules: &PasswordRules = &self.config.password;
+        let len = password.chars().count();
+        if len < rules.min_len || len > rules.max_len {
+            errors.add(field, format!("must be between {} and {} characters", rules.min_len, rules.max_len));
+        }
+
+        let classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|class| **class).count() < rules.min_char_classes {
+            errors.add(field, format!(
+                "must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                rules.min_char_classes));
+        }
+
+        let lowercase = password.to_lowercase();
+        if username.is_some_and(|name| !name.is_empty() && lowercase.contains(&name.to_lowercase())) {
+            errors.add(field, "must not contain the username");
+        }
+        if self.breached.contains(&lowercase) {
+            errors.add(field, "appeared in a data breach, choose another one");
+        }
+    }
+}
+
+fn check_name(rules: &NameRules, errors: &mut FieldErrors, field: &str, name: &str) {
+    let len = name.chars().count();
+    if len < rules.min_len || len > rules.max_len {
+        errors.add(field, format!("must be between {} and {} characters", rules.min_len, rules.max_len));
+    }
+
+    if name.contains(',') {
+        errors.add(field, "must not contain commas");
+    }
+
+    let allowed = |c: char| c.is_alphanumeric() ||
+        rules.symbols.contains(c) ||
+        (rules.allow_spaces && c == ' ');
+    if name.chars().any(|c| c != ',' && !allowed(c)) {
+        let mut kinds = vec!["letters".to_owned(), "digits".to_owned()];
+        if rules.allow_spaces {
+            kinds.push("spaces".to_owned());
+        }
+        if !rules.symbols.is_empty() {
+            kinds.push(rules.symbols.clone());
+        }
+        let last = kinds.pop().expect("Letters and digits are always allowed");
+        errors.add(field, format!("may only contain {} and {last}", kinds.join(", ")));
+    }
+
+    if name.starts_with(' ') || name.ends_with(' ') {
+        errors.add(field, "must not start or end with a space");
+    }
+
+    if rules.reserved.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) {
+        errors.add(field, "is reserved");
+    }
+}
@@ -1,25 +1,62 @@
-use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::Context;
-use axum::{http::header::AUTHORIZATION, RequestPartsExt};
-use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
-use redis::AsyncCommands;
-use serde::Serialize;
-use socketioxide::{extract::{Data, Extension, SocketRef, State}, handler::ConnectHandler, layer::SocketIoLayer, SocketIo, SocketIoBuilder};
-use tracing::{error, event, info, Level};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgConnection;
+use socketioxide::extract::{AckSender, Extension, SocketRef, State, TryData};
+use socketioxide::handler::ConnectHandler;
+use socketioxide::layer::SocketIoLayer;
+use socketioxide::SocketIo;
+use tracing::{error, event, field, info, info_span, Instrument, Level, Span};
 use uuid::Uuid;
-use crate::{auth_extractor::AuthContext, error::AppError, models::{MessageType, UserModel}, routes::group::Message, util::{redis_store, sqlx_ext::SqlxConstraints}, AppState};
-const BEARER_PREFIX: &'static str = "Bearer";
 
+use crate::auth_extractor::AuthContext;
+use crate::error::{AppError, Result};
+use crate::models::{MentionPolicy, MessageType, UserModel, UserRole};
+use crate::push::MessagePush;
+use crate::routes::group::{mark_group_read, user_banned, user_in_group, Message};
+use crate::routes::user::Mention;
+use crate::util::sqlx_ext::{SqlxConstraints, Traced};
+use crate::util::{mentions, redis_store};
+use crate::validation::{FieldErrors, Validate, Validator};
+use crate::{rate_limit, telemetry, AppState};
 
-pub fn layer(state: AppState) -> SocketIoLayer {
+const BEARER_PREFIX: &str = "Bearer ";
+
+
+pub fn layer(state: AppState) -> (SocketIoLayer, SocketIo) {
     let (layer, io)= SocketIo::builder().with_state(state).build_layer();
 
     io.ns("/", on_connection.with(auth_mw));
-    layer 
+    (layer, io)
+}
+
+/// Tells every client to reconnect elsewhere and flushes their presence,
+/// as disconnect handlers don't get to finish once the server stops.
+pub async fn shutdown(io: &SocketIo, state: &mut AppState) {
+    let sockets = io.sockets().unwrap_or_default();
+    info!("Shutting down {} sockets", sockets.len());
+
+    if let Err(e) = io.emit("server_shutdown", ()) {
+        error!("Unable to notify sockets of shutdown: {e}");
+    }
+
+    let users = sockets.iter()
+        .filter_map(|s| s.extensions.get::<UserContext>())
+        .map(|ctx| (ctx.id, ctx.username))
+        .collect::<HashMap<_, _>>();
+    for (user_id, username) in users {
+        let _ = touch_last_seen(&state.db, user_id).await;
+        redis_store::set_offline(&mut state.redis, &username).await;
+    }
+
+    let _ = io.disconnect();
 }
 
-async fn auth_mw(s: SocketRef, State(mut state): State<AppState>) -> crate::error::Result<()> {
+async fn auth_mw(s: SocketRef, State(mut state): State<AppState>) -> Result<()> {
 
     event!(Level::TRACE, "SOCKET PASSING THROUGH MW");
     let auth_header =  s.req_parts()
@@ -30,157 +67,103 @@ async fn auth_mw(s: SocketRef, State(mut state): State<AppState>) -> crate::erro
     let auth_token= auth_header.to_str().
         context("Auth header could not be converted to str")?;
 
-    if !auth_token.starts_with(BEARER_PREFIX) {
+    let Some(token) = auth_token.strip_prefix(BEARER_PREFIX) else {
         event!(Level::TRACE, "Unable to decode JWT");
-        Err(AppError::InvalidToken)
-    } else {
-        let token = &auth_token[(BEARER_PREFIX.len() + 1)..];
-        let user_id= AuthContext::verify_jwt(token)?
-            .claims.sub.parse::<Uuid>().expect("User id should be convertible to Uuid");
-    
-        let user_context = sqlx::query_as!(UserContext,
-            "SELECT id, username FROM users WHERE id = $1",
-             user_id)
-            .fetch_one(&state.db)
-            .await?;
-
-        event!(Level::TRACE, "Decoded JWT {user_context:?}");
-        s.extensions.insert(user_context.clone());
-
-        let _ = redis_store::set_online(
-            &mut state.redis , 
-            &user_context.username)
-            .await;
-
-        Ok(())
-    }
+        return Err(AppError::InvalidToken);
+    };
+
+    let user_id= AuthContext::verify_jwt(token, &state.config.auth)?
+        .claims.sub.parse::<Uuid>().map_err(|_| AppError::InvalidToken)?;
+
+    // Tokens outlive the accounts they were issued for
+    let user_context = sqlx::query_as!(UserContext,
+        "SELECT id, username FROM users WHERE id = $1",
+         user_id)
+        .fetch_optional(&state.db)
+        .traced("auth_mw")
+        .await?
+        .ok_or(AppError::InvalidToken)?;
+
+    event!(Level::TRACE, "Decoded JWT {user_context:?}");
+    s.extensions.insert(user_context.clone());
+
+    let _ = touch_last_seen(&state.db, user_context.id).await;
+
+    let _ = redis_store::set_online(
+        &mut state.redis , 
+        &user_context.username)
+        .await;
+
+    Ok(())
 }
 
-async fn on_connection(
+// Not async, so every handler is registered before the client can send
+// anything after the connect packet
+fn on_connection(
     socket: SocketRef,
 ) {
 
     event!(Level::TRACE, "Socket connected: {}", socket.id);
+    telemetry::socket_connected();
 
     socket.on_disconnect(
         |s: SocketRef, 
         Extension(user_ctx): Extension<UserContext>,
         State(mut state): State<AppState>| 
-        async move {
-            info!("SOCKET DC ON SERVER!!!");
-            redis_store::set_offline(&mut state.redis, &user_ctx.username).await;
-            let _ = s.broadcast()
-                .emit("u_offline", user_ctx.username);
+        {
+            let span = event_span(&s, "disconnect");
+            async move {
+                info!("Socket disconnected");
+                telemetry::socket_disconnected();
+                if let Some(room_id) = joined_room(&s) {
+                    let _ = mark_group_read(&state.db, user_ctx.id, room_id).await;
+                }
+                let _ = touch_last_seen(&state.db, user_ctx.id).await;
+                redis_store::set_offline(&mut state.redis, &user_ctx.username).await;
+                let _ = s.broadcast()
+                    .emit("u_offline", user_ctx.username);
+            }.instrument(span)
         }); 
 
 
     socket.on(
         "join",
         |s: SocketRef,
-         Data(group_id): Data<String>, 
-         Extension(user_ctx): Extension<UserContext>| 
-         async move {
-            event!(Level::TRACE, "JOINED ROOM!");
-            let _ = s.leave_all();
-            let _ = s.join(group_id.clone());
-            let _ = s.within(group_id)
-                .emit("u_online", user_ctx.username);
-        },
+         TryData(group_id): TryData<String>,
+         Extension(user_ctx): Extension<UserContext>,
+         State(state): State<AppState>,
+         ack: AckSender|
+         {
+             let span = event_span(&s, "join");
+             async move {
+                 ack_result(ack, async { on_join(&s, &state, &user_ctx, payload(group_id)?).await }.await);
+             }.instrument(span)
+         },
     );
 
     socket.on(
         "message", 
-        |s: SocketRef, Data(msg): Data<String>,
-         Extension(user_ctx): Extension<UserContext>, State(mut state): State<AppState>|
-        async move{
-            event!(Level::TRACE, "SIGNALING MSG! {msg}");
-            let room = current_room(&s);
-            let room_id = Uuid::from_str(&room).unwrap();
-            let msg_rec = sqlx::query!(r#"
-                INSERT INTO messages(sender_id, receiver_group_id, content, msg_type)
-                VALUES 
-                    ($1, $2, $3, 'normal')
-                RETURNING id, created_at AS date
-            "#, user_ctx.id, room_id, msg)
-                .fetch_one(&state.db).await.unwrap();
-            
-            let _ = s.within(room).emit("message", MessageBody {
-                id: msg_rec.id,
-                sender: user_ctx.username.clone(),
-                content: msg.clone(),
-                date: msg_rec.date
-            });
-
-            let _ = redis_store::append_message(&mut state.redis, room_id, Message {
-                id: msg_rec.id,
-                sender: Some(user_ctx.username),
-                content: msg,
-                msg_type: MessageType::Normal,
-                date: msg_rec.date,
-            }).await;
-    });
+        |s: SocketRef, io: SocketIo, TryData(msg): TryData<String>,
+         Extension(user_ctx): Extension<UserContext>, State(mut state): State<AppState>,
+         ack: AckSender|
+        {
+            let span = event_span(&s, "message");
+            async move {
+                ack_result(ack, async { on_message(&s, &io, &mut state, &user_ctx, payload(msg)?).await }.await);
+            }.instrument(span)
+        }
+    );
 
     socket.on(
         "add_user",
-        |s: SocketRef, Data(username): Data<String>,
-        Extension(user_ctx) : Extension<UserContext>, State(mut state): State<AppState>|
-        async move {
-            event!(Level::TRACE, "WS: ADD USER {username}");
-            let room = current_room(&s);
-            let room_id = Uuid::from_str(&room).unwrap();
-
-            let mut conn = state.db.acquire().await.unwrap();
-
-            let add_res = sqlx::query_as!(UserModel, 
-                "SELECT * FROM users WHERE username = $1", 
-                    username)
-                .fetch_one(conn.as_mut())
-                .await
-                .map_non_existence_err("User", &username);
-
-            match add_res {
-                Ok(add_user) => {
-                    sqlx::query!(r#"
-                        INSERT INTO user_groups(user_id, group_id, role)
-                        VALUES
-                            ($1, $2, 'user')
-                    "#, add_user.id, room_id)
-                        .execute(conn.as_mut())
-                        .await
-                        .unwrap();
-
-                    let join_msg = format!("{} joined.", add_user.username);
-
-                    let msg_rec = sqlx::query!(r#"
-                        INSERT INTO messages(receiver_group_id, content, msg_type)
-                        VALUES
-                            ($1, $2, 'event')
-                        RETURNING id, created_at AS date
-                    "#, room_id, join_msg)
-                        .fetch_one(conn.as_mut())
-                        .await
-                        .unwrap();
-
-
-                    let _ = redis_store::append_message(
-                        &mut state.redis, 
-                        room_id, 
-                        Message {
-                            id: msg_rec.id,
-                            sender: None,
-                            content: join_msg,
-                            msg_type: MessageType::Event,
-                            date: msg_rec.date,
-                    }).await;
-
-                    let is_added_user_online = redis_store::is_online(&mut state.redis, &add_user.username).await;
-                    let _ = s.within(room)
-                        .emit("add_user", format!("{},{},{is_added_user_online}",add_user.username, user_ctx.username));
-                }, 
-                Err(e) => {
-                    event!(Level::TRACE, "{e}");
-                }
-            }
+        |s: SocketRef, TryData(username): TryData<String>,
+        Extension(user_ctx) : Extension<UserContext>, State(mut state): State<AppState>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "add_user");
+            async move {
+                ack_result(ack, async { on_add_user(&s, &mut state, &user_ctx, payload(username)?).await }.await);
+            }.instrument(span)
         }
     );
 
@@ -188,138 +171,772 @@ async fn on_connection(
         "leave",
         |s: SocketRef,
         Extension(user_ctx): Extension<UserContext>, 
-        State(mut state): State<AppState>|
-        async move {
-
-            let room = current_room(&s);
-            let room_id = Uuid::from_str(&room).unwrap();
-
-            let _ = s.within(room)
-                .emit("leave", user_ctx.username.clone());
-            let _ = s.leave_all();
-            let _ = sqlx::query!(r#"
-                DELETE FROM user_groups 
-                WHERE 
-                    user_id = $1 AND 
-                    group_id = $2
-            "#, user_ctx.id, room_id)
-                .execute(&state.db)
-                .await
-                .unwrap();
-
-            let leave_msg = format!("{} left.", user_ctx.username);
-
-            let msg_rec = sqlx::query!(r#"
-                INSERT INTO messages(receiver_group_id, content, msg_type)
-                    VALUES
-                        ($1, $2, 'event')
-                    RETURNING id, created_at AS date
-            "#, room_id, leave_msg)
-                .fetch_one(&state.db)
-                .await
-                .unwrap();
-
-            let _ = redis_store::append_message(
-                &mut state.redis, 
-                room_id, 
-                Message {
-                    id: msg_rec.id,
-                    sender: None,
-                    content: leave_msg,
-                    msg_type: MessageType::Event,
-                    date: msg_rec.date,
-                })
-                .await;
+        State(mut state): State<AppState>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "leave");
+            async move {
+                ack_result(ack, on_leave(&s, &mut state, &user_ctx).await);
+            }.instrument(span)
         }
     );
 
     socket.on(
         "type_start",
-        |s: SocketRef, Extension(user_ctx): Extension<UserContext>|
-        async move {
-            event!(Level::TRACE, "SIGNALING TYPE_START!");
-            let room = current_room(&s);
-            let _ = s.within(room)
-                .emit("type_start", user_ctx.username);
+        |s: SocketRef, Extension(user_ctx): Extension<UserContext>, State(state): State<AppState>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "type_start");
+            async move {
+                ack_result(ack, on_typing(&s, &state, &user_ctx, "type_start").await);
+            }.instrument(span)
         }
     );
 
     socket.on(
-        "type_stop", 
-        |s: SocketRef, Extension(user_ctx): Extension<UserContext>| 
-        async move {
-            event!(Level::TRACE, "SIGNALING TYPE_STOP!");
-            let room = current_room(&s);
-            let _ = s.within(room)
-                .emit("type_stop", user_ctx.username);
+        "type_stop",
+        |s: SocketRef, Extension(user_ctx): Extension<UserContext>, State(state): State<AppState>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "type_stop");
+            async move {
+                ack_result(ack, on_typing(&s, &state, &user_ctx, "type_stop").await);
+            }.instrument(span)
         }
     );
 
     socket.on(
         "kick", 
         |s: SocketRef, 
-        Data(rem_user): Data<String>, 
+        io: SocketIo,
+        TryData(rem_user): TryData<String>, 
+        State(mut state): State<AppState>,
+        Extension(user_ctx): Extension<UserContext>,
+        ack: AckSender| 
+        {
+            let span = event_span(&s, "kick");
+            async move {
+                ack_result(ack, async { on_kick(&s, &io, &mut state, &user_ctx, payload(rem_user)?).await }.await);
+            }.instrument(span)
+        }
+    );
+
+    socket.on(
+        "ban",
+        |s: SocketRef,
+        io: SocketIo,
+        TryData(ban): TryData<BanPayload>,
+        State(mut state): State<AppState>,
+        Extension(user_ctx): Extension<UserContext>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "ban");
+            async move {
+                ack_result(ack, async { on_ban(&s, &io, &mut state, &user_ctx, payload(ban)?).await }.await);
+            }.instrument(span)
+        }
+    );
+
+    socket.on(
+        "pin",
+        |s: SocketRef,
+        TryData(msg_id): TryData<String>,
+        State(mut state): State<AppState>,
+        Extension(user_ctx): Extension<UserContext>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "pin");
+            async move {
+                ack_result(ack, async { on_pin(&s, &mut state, &user_ctx, payload(msg_id)?).await }.await);
+            }.instrument(span)
+        }
+    );
+
+    socket.on(
+        "unpin",
+        |s: SocketRef,
+        TryData(msg_id): TryData<String>,
         State(mut state): State<AppState>,
-        Extension(user_ctx): Extension<UserContext>| 
-        async move {
-            event!(Level::TRACE, "SIGNALING USER kICK");
-            let room = current_room(&s);
-            let room_id = Uuid::parse_str(&room).expect("Room Id should be convertible to Uuid");
-            let res = sqlx::query!(
-            r#"
-                DELETE FROM user_groups 
-                USING users
-                WHERE 
-                    users.id = user_id AND 
-                    users.username = $1 AND 
-                    group_id = $2
-            "#, rem_user, room_id)
-                .execute(&state.db)
-                .await;
-
-            match res {
-                Ok(_) => {
-                    let kicker = user_ctx.username;
-                    let _ = s.within(room)
-                        .emit("kick", format!("{rem_user},{kicker}"));
-
-                    let kick_msg = format!("{rem_user} was kicked out by {kicker}.");
-                    let msg_rec = sqlx::query!(r#"
-                        INSERT INTO 
-                            messages(receiver_group_id, content, msg_type)
-                        VALUES($1, $2, 'event')
-                        RETURNING id, created_at AS date
-                    "#, room_id, kick_msg)
-                        .fetch_one(&state.db)
-                        .await
-                        .unwrap();
-
-                    let _ = redis_store::append_message(
-                        &mut state.redis, 
-                        room_id,
-                        Message {
-                            id: msg_rec.id,
-                            sender: None,
-                            content: kick_msg,
-                            msg_type: MessageType::Event,
-                            date: msg_rec.date,
-                        }
-                    ).await;
-                }, 
-                Err(e) => error!("Unable to remove user from group: {e}"),
-            }
+        Extension(user_ctx): Extension<UserContext>,
+        ack: AckSender|
+        {
+            let span = event_span(&s, "unpin");
+            async move {
+                ack_result(ack, async { on_unpin(&s, &mut state, &user_ctx, payload(msg_id)?).await }.await);
+            }.instrument(span)
         }
     )
 }
 
+// Every socket event ends up here. Failures are logged and counted like http
+// errors, and the sender gets the outcome as the event's ack if it asked for one.
+fn ack_result(ack: AckSender, res: Result<()>) {
+    let body = match res {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => e.report().1,
+    };
+    let _ = ack.send(body);
+}
+
+// Payloads that don't deserialize are answered like any other invalid field
+fn payload<T>(data: serde_json::Result<T>) -> Result<T> {
+    data.map_err(|e| {
+        let mut errors = FieldErrors::default();
+        errors.add("payload", e.to_string());
+        AppError::Validation(errors)
+    })
+}
+
+async fn on_join(
+    s: &SocketRef,
+    state: &AppState,
+    user_ctx: &UserContext,
+    group_id: String
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "join").await?;
+
+    event!(Level::TRACE, "JOINED ROOM!");
+    let room_id = parse_id("group_id", &group_id)?;
+
+    if user_banned(&state.db, user_ctx.id, room_id).await? {
+        event!(Level::TRACE, "{} is banned from {group_id}", user_ctx.username);
+        let _ = s.emit("banned", &group_id);
+        return Err(AppError::ForbiddenAction);
+    }
+    if !user_in_group(&state.db, user_ctx.id, room_id, None).await? {
+        event!(Level::TRACE, "{} is not a member of {group_id}", user_ctx.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    if let Some(prev_room_id) = joined_room(s) {
+        let _ = mark_group_read(&state.db, user_ctx.id, prev_room_id).await;
+    }
+    if let Err(e) = mark_group_read(&state.db, user_ctx.id, room_id).await {
+        error!("Unable to mark room as read: {e}");
+    }
+
+    let _ = s.leave_all();
+    let _ = s.join(group_id.clone());
+    let _ = s.within(group_id)
+        .emit("u_online", &user_ctx.username);
+    Ok(())
+}
+
+async fn on_message(
+    s: &SocketRef,
+    io: &SocketIo,
+    state: &mut AppState,
+    user_ctx: &UserContext,
+    msg: String
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "message").await?;
+
+    let mut errors = FieldErrors::default();
+    state.validator.message(&mut errors, "content", &msg);
+    errors.into_result()?;
+
+    let (room, room_id) = current_room(s)?;
+    let msg_rec = sqlx::query!(r#"
+        INSERT INTO messages(sender_id, receiver_group_id, content, msg_type)
+        VALUES 
+            ($1, $2, $3, 'normal')
+        RETURNING id, created_at AS date
+    "#, user_ctx.id, room_id, msg)
+        .fetch_one(&state.db)
+        .traced("on_message")
+        .await?;
+
+    // Contents stay out of the logs, they end up in log storage
+    event!(Level::TRACE, "SIGNALING MSG! {} ({} bytes)", msg_rec.id, msg.len());
+
+    let msg_body = MessageBody {
+        id: msg_rec.id,
+        sender: user_ctx.username.clone(),
+        content: msg.clone(),
+        date: msg_rec.date
+    };
+
+    let _ = s.within(room).emit("message", &msg_body);
+
+    let mentioned = deliver_mentions(io, state, room_id, user_ctx, &msg_body)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Unable to deliver mentions: {e}");
+            vec![]
+        });
+
+    state.push.enqueue(MessagePush {
+        group_id: room_id,
+        sender_id: user_ctx.id,
+        message_id: msg_rec.id,
+        sender: user_ctx.username.clone(),
+        content: msg.clone(),
+        date: msg_rec.date,
+        mentioned,
+    });
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, Message {
+        id: msg_rec.id,
+        sender: Some(user_ctx.username.clone()),
+        content: msg,
+        msg_type: MessageType::Normal,
+        date: msg_rec.date,
+    }).await;
+    Ok(())
+}
+
+async fn on_add_user(
+    s: &SocketRef,
+    state: &mut AppState,
+    user_ctx: &UserContext,
+    username: String
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "add_user").await?;
+
+    event!(Level::TRACE, "WS: ADD USER {username}");
+    let (room, room_id) = current_room(s)?;
+
+    let add_user = sqlx::query_as!(UserModel, 
+        "SELECT id, username, password_hash FROM users WHERE username = $1", 
+            username)
+        .fetch_one(&state.db)
+        .traced("on_add_user")
+        .await
+        .map_non_existence_err("User", &username)?;
+
+    if user_banned(&state.db, add_user.id, room_id).await? {
+        event!(Level::TRACE, "{} is banned from {room}", add_user.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query!(r#"
+        INSERT INTO user_groups(user_id, group_id, role)
+        VALUES
+            ($1, $2, 'user')
+    "#, add_user.id, room_id)
+        .execute(&mut *tx)
+        .traced("on_add_user")
+        .await
+        .map_unique_err("Member", &add_user.username)?;
+
+    let join_msg = insert_event(&mut tx, room_id, format!("{} joined.", add_user.username)).await?;
+    tx.commit().await?;
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, join_msg).await;
+
+    let is_added_user_online = redis_store::is_online(&mut state.redis, &add_user.username).await
+        .map_or("unknown".to_owned(), |online| online.to_string());
+    let _ = s.within(room)
+        .emit("add_user", format!("{},{},{is_added_user_online}",add_user.username, user_ctx.username));
+    Ok(())
+}
+
+async fn on_leave(
+    s: &SocketRef,
+    state: &mut AppState,
+    user_ctx: &UserContext
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "leave").await?;
+
+    let (room, room_id) = current_room(s)?;
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query!(r#"
+        DELETE FROM user_groups 
+        WHERE 
+            user_id = $1 AND 
+            group_id = $2
+    "#, user_ctx.id, room_id)
+        .execute(&mut *tx)
+        .traced("on_leave")
+        .await?;
+
+    let leave_msg = insert_event(&mut tx, room_id, format!("{} left.", user_ctx.username)).await?;
+    tx.commit().await?;
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, leave_msg).await;
+
+    let _ = s.within(room)
+        .emit("leave", &user_ctx.username);
+    let _ = s.leave_all();
+    Ok(())
+}
+
+async fn on_typing(
+    s: &SocketRef,
+    state: &AppState,
+    user_ctx: &UserContext,
+    event: &'static str
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, event).await?;
+
+    event!(Level::TRACE, "SIGNALING {event}!");
+    let (room, _) = current_room(s)?;
+    let _ = s.within(room)
+        .emit(event, &user_ctx.username);
+    Ok(())
+}
+
+async fn on_kick(
+    s: &SocketRef,
+    io: &SocketIo,
+    state: &mut AppState,
+    user_ctx: &UserContext,
+    rem_user: String
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "kick").await?;
+
+    event!(Level::TRACE, "SIGNALING USER kICK");
+    let (room, room_id) = current_room(s)?;
+
+    if !user_in_group(&state.db, user_ctx.id, room_id, Some(UserRole::Admin)).await? {
+        event!(Level::TRACE, "{} is not eligible to kick in {room}", user_ctx.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let kicker = &user_ctx.username;
+
+    let mut tx = state.db.begin().await?;
+    let rem_rec = sqlx::query!(
+    r#"
+        SELECT
+            ug.user_id,
+            ug.role AS "role: UserRole"
+        FROM user_groups AS ug
+        INNER JOIN users
+        ON users.id = ug.user_id
+        WHERE 
+            users.username = $1 AND 
+            ug.group_id = $2
+        FOR UPDATE OF ug
+    "#, rem_user, room_id)
+        .fetch_optional(&mut *tx)
+        .traced("on_kick")
+        .await?
+        .ok_or_else(|| AppError::DoesNotExist {
+            target_type: "Member".to_owned(),
+            data: rem_user.clone(),
+        })?;
+
+    // Admins can't be kicked, so a group never loses its last one this way
+    if rem_rec.user_id == user_ctx.id || matches!(rem_rec.role, UserRole::Admin) {
+        event!(Level::TRACE, "{} can't kick {rem_user} in {room}", user_ctx.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    sqlx::query!(r#"
+        DELETE FROM user_groups 
+        WHERE 
+            user_id = $1 AND 
+            group_id = $2
+    "#, rem_rec.user_id, room_id)
+        .execute(&mut *tx)
+        .traced("on_kick")
+        .await?;
+
+    let kick_msg = insert_event(&mut tx, room_id, format!("{rem_user} was kicked out by {kicker}.")).await?;
+    tx.commit().await?;
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, kick_msg).await;
+
+    let _ = s.within(room.clone())
+        .emit("kick", format!("{rem_user},{kicker}"));
+
+    remove_user_from_room(io, rem_rec.user_id, &room, "kicked");
+    Ok(())
+}
+
+async fn on_ban(
+    s: &SocketRef,
+    io: &SocketIo,
+    state: &mut AppState,
+    user_ctx: &UserContext,
+    ban: BanPayload
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "ban").await?;
+
+    event!(Level::TRACE, "SIGNALING USER BAN");
+    let (room, room_id) = current_room(s)?;
+
+    if !user_in_group(&state.db, user_ctx.id, room_id, Some(UserRole::Admin)).await? {
+        event!(Level::TRACE, "{} is not eligible to ban in {room}", user_ctx.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    ban.validate(&state.validator)?;
+    let banner = &user_ctx.username;
+
+    let mut tx = state.db.begin().await?;
+    let target = sqlx::query!(r#"
+        SELECT
+            users.id,
+            ug.role AS "role?: UserRole"
+        FROM users
+        LEFT JOIN user_groups AS ug
+        ON 
+            ug.user_id = users.id AND
+            ug.group_id = $2
+        WHERE users.username = $1
+        FOR UPDATE OF users
+    "#, ban.username, room_id)
+        .fetch_one(&mut *tx)
+        .traced("on_ban")
+        .await
+        .map_non_existence_err("User", &ban.username)?;
+
+    // Like kicks, admins are out of reach
+    if target.id == user_ctx.id || matches!(target.role, Some(UserRole::Admin)) {
+        event!(Level::TRACE, "{} can't ban {} in {room}", user_ctx.username, ban.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let banned_id = sqlx::query!(r#"
+        INSERT INTO group_bans(group_id, user_id, banned_by, reason, expires_at)
+        VALUES ($1, $5, $2, $3, now() + $4::bigint * interval '1 second')
+        ON CONFLICT (group_id, user_id) DO UPDATE
+        SET 
+            banned_by = EXCLUDED.banned_by,
+            reason = EXCLUDED.reason,
+            expires_at = EXCLUDED.expires_at,
+            created_at = now()
+        RETURNING user_id
+    "#, room_id, user_ctx.id, ban.reason, ban.duration_secs, target.id)
+        .fetch_one(&mut *tx)
+        .traced("on_ban")
+        .await?
+        .user_id;
+
+    sqlx::query!(r#"
+        DELETE FROM user_groups 
+        WHERE 
+            user_id = $1 AND 
+            group_id = $2
+    "#, banned_id, room_id)
+        .execute(&mut *tx)
+        .traced("on_ban")
+        .await?;
+
+    let ban_msg = insert_event(&mut tx, room_id, format!("{} was banned by {banner}.", ban.username)).await?;
+    tx.commit().await?;
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, ban_msg).await;
+
+    let _ = s.within(room.clone())
+        .emit("ban", format!("{},{banner}", ban.username));
+
+    remove_user_from_room(io, banned_id, &room, "banned");
+    Ok(())
+}
+
+async fn on_pin(
+    s: &SocketRef,
+    state: &mut AppState,
+    user_ctx: &UserContext,
+    msg_id: String
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "pin").await?;
+
+    event!(Level::TRACE, "SIGNALING MSG PIN");
+    let (room, room_id) = current_room(s)?;
+    let msg_id = parse_id("message_id", &msg_id)?;
+
+    if !user_in_group(&state.db, user_ctx.id, room_id, Some(UserRole::Admin)).await? {
+        event!(Level::TRACE, "{} is not eligible to pin in {room}", user_ctx.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let mut tx = state.db.begin().await?;
+    let pinned = sqlx::query!(r#"
+        INSERT INTO pinned_messages(group_id, message_id, pinned_by)
+        SELECT receiver_group_id, id, $3
+        FROM messages
+        WHERE 
+            id = $1 AND 
+            receiver_group_id = $2 AND
+            msg_type = 'normal'
+        RETURNING message_id
+    "#, msg_id, room_id, user_ctx.id)
+        .fetch_optional(&mut *tx)
+        .traced("on_pin")
+        .await
+        .map_unique_err("Pin", &msg_id.to_string())?;
+
+    // Events and other groups' messages can't be pinned here either
+    if pinned.is_none() {
+        return Err(AppError::DoesNotExist {
+            target_type: "Message".to_owned(),
+            data: msg_id.to_string(),
+        });
+    }
+
+    let pin_msg = insert_event(&mut tx, room_id, format!("{} pinned a message.", user_ctx.username)).await?;
+    tx.commit().await?;
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, pin_msg).await;
+
+    let _ = s.within(room).emit("pinned", PinBody {
+        id: msg_id,
+        pinned_by: user_ctx.username.clone(),
+    });
+    Ok(())
+}
+
+async fn on_unpin(
+    s: &SocketRef,
+    state: &mut AppState,
+    user_ctx: &UserContext,
+    msg_id: String
+) -> Result<()> {
+    rate_limit::limit_event(state, s, user_ctx.id, "unpin").await?;
+
+    event!(Level::TRACE, "SIGNALING MSG UNPIN");
+    let (room, room_id) = current_room(s)?;
+    let msg_id = parse_id("message_id", &msg_id)?;
+
+    if !user_in_group(&state.db, user_ctx.id, room_id, Some(UserRole::Admin)).await? {
+        event!(Level::TRACE, "{} is not eligible to unpin in {room}", user_ctx.username);
+        return Err(AppError::ForbiddenAction);
+    }
+
+    let mut tx = state.db.begin().await?;
+    let unpinned = sqlx::query!(r#"
+        DELETE FROM pinned_messages
+        WHERE
+            message_id = $1 AND
+            group_id = $2
+        RETURNING message_id
+    "#, msg_id, room_id)
+        .fetch_optional(&mut *tx)
+        .traced("on_unpin")
+        .await?;
+
+    if unpinned.is_none() {
+        return Err(AppError::DoesNotExist {
+            target_type: "Pin".to_owned(),
+            data: msg_id.to_string(),
+        });
+    }
+
+    let unpin_msg = insert_event(&mut tx, room_id, format!("{} unpinned a message.", user_ctx.username)).await?;
+    tx.commit().await?;
+
+    let _ = redis_store::append_message(&mut state.redis, room_id, unpin_msg).await;
+
+    let _ = s.within(room).emit("unpinned", UnpinBody {
+        id: msg_id,
+        unpinned_by: user_ctx.username.clone(),
+    });
+    Ok(())
+}
+
+// Resolves the mentions in a message against the group members, stores them
+// and notifies every socket of the mentioned users, whichever room they are in.
+// Returns the ids of the mentioned users.
+async fn deliver_mentions(
+    io: &SocketIo,
+    state: &mut AppState,
+    room_id: Uuid,
+    sender: &UserContext,
+    msg: &MessageBody
+) -> Result<Vec<Uuid>> {
+    let parsed = mentions::parse(&msg.content);
+    if parsed.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let group = sqlx::query!(r#"
+        SELECT 
+            name, 
+            mention_policy AS "mention_policy: MentionPolicy"
+        FROM groups 
+        WHERE id = $1
+    "#, room_id)
+        .fetch_one(&state.db)
+        .traced("deliver_mentions")
+        .await?;
+
+    let broadcast_allowed = (parsed.here || parsed.all) && match group.mention_policy {
+        MentionPolicy::Everyone => true,
+        MentionPolicy::Admins => user_in_group(&state.db, sender.id, room_id, Some(UserRole::Admin)).await?,
+        MentionPolicy::Nobody => false,
+    };
+
+    let members = sqlx::query!(r#"
+        SELECT 
+            users.id, 
+            users.username,
+            (user_groups.notify = 'none' OR COALESCE(user_groups.muted_until > now(), false)) AS "muted!"
+        FROM user_groups
+        INNER JOIN users
+        ON users.id = user_groups.user_id
+        WHERE 
+            user_groups.group_id = $1 AND
+            users.id <> $2
+    "#, room_id, sender.id)
+        .fetch_all(&state.db)
+        .traced("deliver_mentions")
+        .await?;
+
+    // Only @here needs presence, looked up for every member at once
+    let online = if broadcast_allowed && !parsed.all {
+        let usernames = members.iter().map(|m| m.username.as_str()).collect::<Vec<_>>();
+        redis_store::are_online(&mut state.redis, &usernames).await
+    } else {
+        None
+    };
+
+    let mut mentioned = vec![];
+    let mut muted = HashSet::new();
+    for (i, m) in members.into_iter().enumerate() {
+        if m.muted {
+            muted.insert(m.id);
+        }
+
+        let is_mentioned = parsed.usernames.contains(&m.username) || (broadcast_allowed && (
+            parsed.all || online.as_ref().is_some_and(|online| online[i])
+        ));
+
+        if is_mentioned {
+            mentioned.push(m.id);
+        }
+    }
+
+    if mentioned.is_empty() {
+        return Ok(mentioned);
+    }
+
+    let mention_recs = sqlx::query!(r#"
+        INSERT INTO mentions(message_id, group_id, user_id)
+        SELECT $1, $2, UNNEST($3::uuid[])
+        ON CONFLICT (message_id, user_id) DO NOTHING
+        RETURNING id, user_id
+    "#, msg.id, room_id, &mentioned)
+        .fetch_all(&state.db)
+        .traced("deliver_mentions")
+        .await?;
+
+    // Muted users still get the mention in their feed, just no live event
+    for rec in mention_recs.into_iter().filter(|rec| !muted.contains(&rec.user_id)) {
+        let mention = Mention {
+            id: rec.id,
+            message_id: msg.id,
+            group_id: room_id,
+            group_name: group.name.clone(),
+            sender: Some(sender.username.clone()),
+            content: msg.content.clone(),
+            date: msg.date,
+            read: false,
+        };
+
+        for sock in user_sockets(io, rec.user_id) {
+            let _ = sock.emit("mentioned", &mention);
+        }
+    }
+
+    Ok(mentioned)
+}
+
+// Stores an event message for the group as part of a transaction. Sockets
+// and the message cache only hear of it once the transaction commits, so
+// they never see an event that was rolled back.
+pub async fn insert_event(
+    conn: &mut PgConnection,
+    room_id: Uuid,
+    content: String
+) -> Result<Message> {
+    let msg_rec = sqlx::query!(r#"
+        INSERT INTO 
+            messages(receiver_group_id, content, msg_type)
+        VALUES($1, $2, 'event')
+        RETURNING id, created_at AS date
+    "#, room_id, content)
+        .fetch_one(conn)
+        .traced("insert_event")
+        .await?;
+
+    Ok(Message {
+        id: msg_rec.id,
+        sender: None,
+        content,
+        msg_type: MessageType::Event,
+        date: msg_rec.date,
+    })
+}
 
+// Forces every socket of the user out of the room, notifying them with
+// `event` so the client can navigate away.
+fn remove_user_from_room(io: &SocketIo, user_id: Uuid, room: &str, event: &'static str) {
+    for sock in user_sockets(io, user_id) {
+        let _ = sock.leave(room.to_owned());
+        let _ = sock.emit(event, room);
+    }
+}
+
+/// Tells every socket of the user why and closes them, e.g. once the
+/// account is gone.
+pub fn disconnect_user(io: &SocketIo, user_id: Uuid, event: &'static str) {
+    for sock in user_sockets(io, user_id) {
+        let _ = sock.emit(event, ());
+        let _ = sock.disconnect();
+    }
+}
+
+/// Tells the group who runs it now, once the event message announcing it
+/// is committed.
+pub fn announce_admin(io: &SocketIo, group_id: Uuid, username: &str) {
+    let _ = io.within(group_id.to_string()).emit("admin", username);
+}
+
+fn user_sockets(io: &SocketIo, user_id: Uuid) -> Vec<SocketRef> {
+    io.sockets()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|sock| sock.extensions
+            .get::<UserContext>()
+            .is_some_and(|ctx| ctx.id == user_id))
+        .collect()
+}
+
+async fn touch_last_seen(db: &sqlx::PgPool, user_id: Uuid) -> Result<()> {
+    sqlx::query!("UPDATE users SET last_seen_at = now() WHERE id = $1", user_id)
+        .execute(db)
+        .traced("touch_last_seen")
+        .await?;
+    Ok(())
+}
+
+// Every event handler runs in a span carrying the socket and its user,
+// so a complaint can be traced to the log lines of that socket.
+fn event_span(s: &SocketRef, event: &'static str) -> Span {
+    telemetry::socket_event(event);
+    let user_id = s.extensions.get::<UserContext>().map(|ctx| ctx.id);
+    info_span!("socket_event", socket_id = %s.id, user_id = user_id.map(field::display), event)
+}
 
-fn current_room(s: &SocketRef) -> String {
+fn joined_room(s: &SocketRef) -> Option<Uuid> {
     s.rooms()
-        .expect("A socket should be connected to a group")
+        .ok()?
         .first()
-        .expect("A socket should only be connected to one room")
-        .to_string()
+        .and_then(|room| Uuid::parse_str(room).ok())
+}
+
+// The group a socket joined, events about a group need one
+fn current_room(s: &SocketRef) -> Result<(String, Uuid)> {
+    joined_room(s)
+        .map(|room_id| (room_id.to_string(), room_id))
+        .ok_or_else(|| AppError::Rejected {
+            status: StatusCode::CONFLICT,
+            msg: "Join a group first".to_owned(),
+        })
+}
+
+fn parse_id(field: &str, id: &str) -> Result<Uuid> {
+    Uuid::parse_str(id).map_err(|_| {
+        let mut errors = FieldErrors::default();
+        errors.add(field, format!("`{id}` is not a valid uuid"));
+        AppError::Validation(errors)
+    })
 }
 
 #[derive(Serialize)]
@@ -331,6 +948,35 @@ struct MessageBody {
 }
 
 
+#[derive(Serialize)]
+struct PinBody {
+    id: Uuid,
+    pinned_by: String,
+}
+
+#[derive(Serialize)]
+struct UnpinBody {
+    id: Uuid,
+    unpinned_by: String,
+}
+
+#[derive(Deserialize)]
+struct BanPayload {
+    username: String,
+    reason: Option<String>,
+    duration_secs: Option<i64>,
+}
+
+impl Validate for BanPayload {
+    fn validate(&self, _validator: &Validator) -> Result<()> {
+        let mut errors = FieldErrors::default();
+        if self.duration_secs.is_some_and(|secs| secs <= 0) {
+            errors.add("duration_secs", "must be positive, leave it out for a permanent ban");
+        }
+        errors.into_result()
+    }
+}
+
 #[derive(Debug, Clone)]
 struct UserContext {
     id: Uuid,
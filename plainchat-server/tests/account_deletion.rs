@@ -0,0 +1,33 @@
+mod common;
+
+use std::time::Duration;
+
+use axum::http::{Method, StatusCode};
+use common::{TestApp, PASSWORD};
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn deleted_accounts_lose_their_tokens_and_groups_hear_of_new_admins() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut socket = app.connect(&member).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+
+    let (status, body) = app.request(Method::DELETE, "/api/user", &admin, Some(json!({ "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    // The token is still a valid jwt, only the account is gone
+    let (status, body) = app.request(Method::GET, "/api/user/mentions", &admin, None).await;
+    assert_eq!((status, &body["error"]["token"]), (StatusCode::BAD_REQUEST, &json!("invalid")), "{body}");
+
+    let events = socket.events(Duration::from_millis(300)).await;
+    assert!(events.contains(&("admin".to_owned(), json!(member.username))), "{events:?}");
+
+    let (status, body) = app.request(Method::GET, &format!("/api/group/{group_id}/messages"), &member, None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert!(body.to_string().contains(&format!("{} is now the admin.", member.username)), "{body}");
+}
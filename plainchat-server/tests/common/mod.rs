@@ -0,0 +1,261 @@
+//! Test harness running the whole app against the postgres and redis given
+//! by `DATABASE_URL` and `REDIS_URL`, the same ones the server uses. Tests
+//! using it are `#[ignore]`d, run them with `cargo test -- --ignored`.
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use futures_util::{SinkExt, StreamExt};
+use plainchat_server::auth_extractor::AuthContext;
+use plainchat_server::config::Config;
+use plainchat_server::util::pass_hash;
+use plainchat_server::AppState;
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+pub const PASSWORD: &str = "Test-password1";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestApp {
+    pub state: AppState,
+    pub router: Router,
+    pub addr: SocketAddr,
+}
+
+pub struct TestUser {
+    pub id: Uuid,
+    pub username: String,
+    pub token: String,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Self {
+        Self::spawn_with(|_| {}).await
+    }
+
+    /// Starts the app with `configure` applied to the config.
+    pub async fn spawn_with(configure: impl FnOnce(&mut Config)) -> Self {
+        dotenv::dotenv().ok();
+        let db_url = dotenv::var("DATABASE_URL").expect("DATABASE_URL should be set to run this test");
+        let redis_url = dotenv::var("REDIS_URL").expect("REDIS_URL should be set to run this test");
+
+        let mut config = Config::default();
+        config.database.url = db_url;
+        config.database.max_connections = 10;
+        config.redis.url = redis_url;
+        config.auth.jwt_secret = "test-secret".to_owned();
+        config.rate_limit.enabled = false;
+        configure(&mut config);
+
+        let state = AppState::connect(Arc::new(config)).await
+            .expect("App should connect to postgres and redis");
+        let (router, _io) = plainchat_server::app(state.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = router.clone().into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        Self { state, router, addr }
+    }
+
+    pub async fn create_user(&self) -> TestUser {
+        let username = format!("t{}", &Uuid::new_v4().simple().to_string()[..16]);
+        let hash = pass_hash::hash_password(PASSWORD.to_owned()).await.unwrap();
+        let id = sqlx::query_scalar::<_, Uuid>(
+            "INSERT INTO users(username, password_hash) VALUES ($1, $2) RETURNING id")
+            .bind(&username)
+            .bind(hash)
+            .fetch_one(&self.state.db)
+            .await
+            .unwrap();
+
+        let token = AuthContext(id).generate_jwt(&self.state.config.auth);
+        TestUser { id, username, token }
+    }
+
+    /// Group administered by `admin`, with `members` as plain users.
+    pub async fn create_group(&self, admin: &TestUser, members: &[&TestUser]) -> Uuid {
+        let group_id = sqlx::query_scalar::<_, Uuid>(
+            "INSERT INTO groups(name) VALUES ('test group') RETURNING id")
+            .fetch_one(&self.state.db)
+            .await
+            .unwrap();
+
+        self.add_member(group_id, admin, "admin").await;
+        for member in members {
+            self.add_member(group_id, member, "user").await;
+        }
+        group_id
+    }
+
+    async fn add_member(&self, group_id: Uuid, user: &TestUser, role: &str) {
+        sqlx::query("INSERT INTO user_groups(user_id, group_id, role) VALUES ($1, $2, $3::user_role)")
+            .bind(user.id)
+            .bind(group_id)
+            .bind(role)
+            .execute(&self.state.db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn request(
+        &self,
+        method: Method,
+        uri: &str,
+        user: &TestUser,
+        body: Option<Value>
+    ) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, &user.token)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap();
+
+        let res = self.router.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    pub async fn connect(&self, user: &TestUser) -> TestSocket {
+        TestSocket::connect(self.addr, &user.token).await
+    }
+}
+
+/// Minimal socket.io client speaking engine.io v4 over a websocket.
+pub struct TestSocket {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    next_ack: u64,
+    events: VecDeque<(String, Value)>,
+}
+
+enum Packet {
+    Connect,
+    Event(String, Value),
+    Ack(u64, Value),
+    Other,
+}
+
+impl TestSocket {
+    async fn connect(addr: SocketAddr, token: &str) -> Self {
+        let mut req = format!("ws://{addr}/socket.io/?EIO=4&transport=websocket")
+            .into_client_request()
+            .unwrap();
+        req.headers_mut().insert(AUTHORIZATION, token.parse().unwrap());
+
+        let (ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+        let mut socket = Self { ws, next_ack: 0, events: VecDeque::new() };
+
+        socket.send("40").await;
+        loop {
+            if let Packet::Connect = socket.recv().await.expect("Socket should be accepted") {
+                return socket;
+            }
+        }
+    }
+
+    /// Emits `event` and waits for its ack.
+    pub async fn emit(&mut self, event: &str, data: Option<Value>) -> Value {
+        let id = self.next_ack;
+        self.next_ack += 1;
+
+        let mut args = vec![Value::from(event)];
+        args.extend(data);
+        self.send(&format!("42{id}{}", Value::Array(args))).await;
+
+        loop {
+            match self.recv().await.expect("Event should be acked") {
+                Packet::Ack(ack_id, body) if ack_id == id => return body,
+                Packet::Event(name, data) => self.events.push_back((name, data)),
+                _ => {},
+            }
+        }
+    }
+
+    /// Next event the server pushed, None if it doesn't come within `wait`.
+    pub async fn next_event(&mut self, wait: Duration) -> Option<(String, Value)> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+
+        tokio::time::timeout(wait, async {
+            loop {
+                match self.recv().await? {
+                    Packet::Event(name, data) => return Some((name, data)),
+                    _ => continue,
+                }
+            }
+        }).await.ok().flatten()
+    }
+
+    /// Every event pushed within `wait`.
+    pub async fn events(&mut self, wait: Duration) -> Vec<(String, Value)> {
+        let mut events = vec![];
+        while let Some(event) = self.next_event(wait).await {
+            events.push(event);
+        }
+        events
+    }
+
+    async fn send(&mut self, packet: &str) {
+        self.ws.send(WsMessage::text(packet)).await.unwrap();
+    }
+
+    // Next socket.io packet, answering pings on the way. None once closed.
+    async fn recv(&mut self) -> Option<Packet> {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("Server should answer in time")?
+                .ok()?;
+            let WsMessage::Text(text) = msg else { continue };
+
+            match text.as_bytes().first() {
+                Some(b'2') => self.send("3").await,
+                Some(b'4') => return Some(parse_packet(&text[1..])),
+                Some(b'1') => return None,
+                _ => {},
+            }
+        }
+    }
+}
+
+fn parse_packet(packet: &str) -> Packet {
+    let (kind, rest) = packet.split_at(1);
+    let id_len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let (id, data) = rest.split_at(id_len);
+    let data = serde_json::from_str::<Value>(data).unwrap_or(Value::Null);
+
+    match kind {
+        "0" => Packet::Connect,
+        "2" => match data {
+            Value::Array(mut args) if !args.is_empty() => {
+                let name = args.remove(0).as_str().unwrap_or_default().to_owned();
+                Packet::Event(name, args.into_iter().next().unwrap_or(Value::Null))
+            },
+            _ => Packet::Other,
+        },
+        "3" => Packet::Ack(
+            id.parse().unwrap_or_default(),
+            data.as_array().and_then(|args| args.first()).cloned().unwrap_or(Value::Null)
+        ),
+        _ => Packet::Other,
+    }
+}
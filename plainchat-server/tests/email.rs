@@ -0,0 +1,40 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::TestApp;
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn new_email_replaces_the_old_once_verified() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user().await;
+    let old = format!("{}@old.example.com", user.username);
+    let new = format!("{}@new.example.com", user.username);
+    sqlx::query("UPDATE users SET email = $1, email_verified_at = now() WHERE id = $2")
+        .bind(&old)
+        .bind(user.id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app.request(Method::PUT, "/api/user/email", &user, Some(json!({ "email": new }))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body, json!({ "email": old, "verified": true, "pending_email": new }));
+
+    // The token the mail carried was stored before sending it
+    let token = sqlx::query_scalar::<_, String>("SELECT email_token FROM users WHERE id = $1")
+        .bind(user.id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app.request(
+        Method::POST, "/api/user/email/verify", &user, Some(json!({ "token": "wrong" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{body}");
+
+    let (status, body) = app.request(
+        Method::POST, "/api/user/email/verify", &user, Some(json!({ "token": token }))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body, json!({ "email": new, "verified": true, "pending_email": null }));
+}
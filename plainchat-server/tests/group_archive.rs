@@ -0,0 +1,86 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use common::TestApp;
+use serde_json::json;
+use uuid::Uuid;
+
+// Members, roles, senders and contents as the db holds them
+async fn history(app: &TestApp, group_id: Uuid) -> (Vec<(Uuid, String)>, Vec<(Option<Uuid>, String)>) {
+    let members = sqlx::query_as::<_, (Uuid, String)>(
+        "SELECT user_id, role::text FROM user_groups WHERE group_id = $1 ORDER BY user_id")
+        .bind(group_id)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    let messages = sqlx::query_as::<_, (Option<Uuid>, String)>(
+        "SELECT sender_id, content FROM messages WHERE receiver_group_id = $1 ORDER BY created_at, id")
+        .bind(group_id)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    (members, messages)
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn export_then_import_keeps_the_history() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let content = "x".repeat(app.state.config.validation.message.max_len);
+    for (user, content) in [(&admin, "hello"), (&member, content.as_str()), (&admin, "bye")] {
+        let mut socket = app.connect(user).await;
+        assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+        assert_eq!(socket.emit("message", Some(json!(content))).await, json!({ "ok": true }));
+    }
+
+    let (status, mut archive) = app.request(
+        Method::GET, &format!("/api/group/{group_id}/export"), &admin, None).await;
+    assert_eq!(status, StatusCode::OK, "{archive}");
+    // A sender nobody here goes by
+    let date = archive["exported_at"].clone();
+    archive["messages"].as_array_mut().unwrap().push(json!({
+        "sender": "nobody-at-all",
+        "content": "from elsewhere",
+        "msg_type": "Normal",
+        "date": date,
+    }));
+
+    let (status, summary) = app.request(
+        Method::POST, "/api/group/import?add_members=true", &admin, Some(archive)).await;
+    assert_eq!(status, StatusCode::OK, "{summary}");
+    assert_eq!(summary["members"], json!([member.username]));
+    assert_eq!(summary["unmatched_users"], json!(["nobody-at-all"]));
+
+    let imported_id = summary["id"].as_str().unwrap().parse().unwrap();
+    let (members, mut messages) = history(&app, imported_id).await;
+    let original = history(&app, group_id).await;
+    assert_eq!(members, original.0);
+    assert_eq!(messages.pop(), Some((None, "from elsewhere".to_owned())));
+    assert_eq!(messages, original.1);
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn import_only_adds_members_when_asked() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    // The importer runs the group even as a plain member of the original
+    let (_, archive) = app.request(
+        Method::GET, &format!("/api/group/{group_id}/export"), &admin, None).await;
+    let (status, summary) = app.request(
+        Method::POST, "/api/group/import", &member, Some(archive)).await;
+    assert_eq!(status, StatusCode::OK, "{summary}");
+    assert_eq!(summary["members"], json!([]));
+    assert_eq!(summary["invitees"], json!([admin.username]));
+
+    let imported_id = summary["id"].as_str().unwrap().parse().unwrap();
+    let (members, _) = history(&app, imported_id).await;
+    assert_eq!(members, [(member.id, "admin".to_owned())]);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use std::collections::BTreeSet;
+
+use axum::http::{Method, StatusCode};
+use common::TestApp;
+use plainchat_server::util::redis_store;
+use serde_json::json;
+use uuid::Uuid;
+
+const CACHE_SIZE: usize = 20;
+const ROUNDS: usize = 10;
+const MESSAGES_PER_ROUND: usize = 15;
+const READS_PER_ROUND: usize = 15;
+
+// Ids of the newest messages of the group, as the cache should hold them
+async fn db_window(app: &TestApp, group_id: Uuid) -> BTreeSet<Uuid> {
+    sqlx::query_scalar::<_, Uuid>(r#"
+        SELECT id
+        FROM messages
+        WHERE receiver_group_id = $1
+        ORDER BY created_at DESC, id DESC
+        LIMIT $2
+    "#)
+        .bind(group_id)
+        .bind(CACHE_SIZE as i64)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap()
+        .into_iter()
+        .collect()
+}
+
+async fn cached_window(app: &TestApp, group_id: Uuid) -> Option<BTreeSet<Uuid>> {
+    let mut redis = app.state.redis.clone();
+    redis_store::get_messages(&mut redis, group_id)
+        .await
+        .map(|msgs| msgs.into_iter().map(|msg| msg.id).collect())
+}
+
+// Populating the cache from the db while messages are appended to it must
+// never leave a cache missing a message or holding one twice.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "needs postgres and redis"]
+async fn cache_matches_db_after_concurrent_population_and_appends() {
+    let app = TestApp::spawn_with(|config| config.redis.message_cache_size = CACHE_SIZE).await;
+    let admin = app.create_user().await;
+    let reader = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&reader]).await;
+    let uri = format!("/api/group/{group_id}/messages");
+
+    let mut socket = app.connect(&admin).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+
+    for round in 0..ROUNDS {
+        let mut redis = app.state.redis.clone();
+        redis_store::invalidate_messages(&mut redis, &[group_id]).await.unwrap();
+
+        let writes = async {
+            for i in 0..MESSAGES_PER_ROUND {
+                let ack = socket.emit("message", Some(json!(format!("round {round} message {i}")))).await;
+                assert_eq!(ack, json!({ "ok": true }));
+            }
+        };
+        let reads = async {
+            let reads = (0..READS_PER_ROUND).map(|_| {
+                let (app, reader, uri) = (&app, &reader, &uri);
+                async move {
+                    let (status, _) = app.request(Method::GET, uri, reader, None).await;
+                    assert_eq!(status, StatusCode::OK);
+                }
+            });
+            futures_util::future::join_all(reads).await;
+        };
+        tokio::join!(writes, reads);
+
+        // A population that lost the race may leave no cache, never a wrong one
+        let db = db_window(&app, group_id).await;
+        if let Some(cached) = cached_window(&app, group_id).await {
+            assert_eq!(cached, db, "cache diverged from the db in round {round}");
+        }
+
+        let (status, _) = app.request(Method::GET, &uri, &reader, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cached_window(&app, group_id).await, Some(db), "cache not repopulated in round {round}");
+    }
+}
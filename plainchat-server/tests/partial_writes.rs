@@ -0,0 +1,216 @@
+mod common;
+
+use std::time::Duration;
+
+use axum::http::{Method, StatusCode};
+use common::{TestApp, TestSocket, TestUser, PASSWORD};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+const QUIET: Duration = Duration::from_millis(300);
+
+// Makes the last step of a multi-step write fail, after the earlier ones
+// already ran in the transaction
+struct Failure<'a> {
+    app: &'a TestApp,
+    name: String,
+}
+
+impl<'a> Failure<'a> {
+    // Fails inserting the event message announcing a change to the group
+    async fn on_event(app: &'a TestApp, group_id: Uuid) -> Self {
+        Self::install(app, "messages", "INSERT", format!(
+            "NEW.receiver_group_id = '{group_id}' AND NEW.msg_type = 'event'"
+        )).await
+    }
+
+    async fn on_user_delete(app: &'a TestApp, user_id: Uuid) -> Self {
+        Self::install(app, "users", "DELETE", format!("OLD.id = '{user_id}'")).await
+    }
+
+    async fn install(app: &'a TestApp, table: &str, op: &str, condition: String) -> Self {
+        let name = format!("fail_{}", Uuid::new_v4().simple());
+        sqlx::raw_sql(&format!(r#"
+            CREATE FUNCTION {name}() RETURNS trigger AS $$
+            BEGIN
+                IF {condition} THEN
+                    RAISE EXCEPTION 'injected failure';
+                END IF;
+                RETURN COALESCE(NEW, OLD);
+            END
+            $$ LANGUAGE plpgsql;
+
+            CREATE TRIGGER {name} BEFORE {op} ON {table}
+            FOR EACH ROW EXECUTE FUNCTION {name}();
+        "#))
+            .execute(&app.state.db)
+            .await
+            .unwrap();
+
+        Self { app, name }
+    }
+
+    async fn remove(self) {
+        sqlx::raw_sql(&format!("DROP FUNCTION {} CASCADE", self.name))
+            .execute(&self.app.state.db)
+            .await
+            .unwrap();
+    }
+}
+
+fn assert_server_error(ack: &Value) {
+    assert_eq!(ack["error"]["server"], "db", "Expected a db error, got {ack}");
+}
+
+async fn role(app: &TestApp, group_id: Uuid, user: &TestUser) -> Option<String> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT role::text FROM user_groups WHERE group_id = $1 AND user_id = $2")
+        .bind(group_id)
+        .bind(user.id)
+        .fetch_optional(&app.state.db)
+        .await
+        .unwrap()
+}
+
+async fn event_messages(app: &TestApp, group_id: Uuid) -> i64 {
+    sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM messages WHERE receiver_group_id = $1 AND msg_type = 'event'")
+        .bind(group_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap()
+}
+
+async fn joined(app: &TestApp, user: &TestUser, group_id: Uuid) -> TestSocket {
+    let mut socket = app.connect(user).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+    socket
+}
+
+// Nothing but `allowed` reached the socket, and it is still in the room
+async fn assert_untouched(socket: &mut TestSocket, allowed: &[&str]) {
+    let events = socket.events(QUIET).await;
+    assert!(
+        events.iter().all(|(event, _)| allowed.contains(&event.as_str())),
+        "Unexpected events {events:?}"
+    );
+    assert_eq!(socket.emit("type_start", None).await, json!({ "ok": true }));
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn failed_ban_leaves_no_trace() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut admin_socket = joined(&app, &admin, group_id).await;
+    let mut member_socket = joined(&app, &member, group_id).await;
+
+    let failure = Failure::on_event(&app, group_id).await;
+    let ack = admin_socket.emit("ban", Some(json!({ "username": member.username, "reason": "spam" }))).await;
+    failure.remove().await;
+    assert_server_error(&ack);
+
+    let bans = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM group_bans WHERE group_id = $1")
+        .bind(group_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(bans, 0);
+    assert_eq!(role(&app, group_id, &member).await.as_deref(), Some("user"));
+    assert_eq!(event_messages(&app, group_id).await, 0);
+
+    assert_untouched(&mut admin_socket, &["u_online"]).await;
+    assert_untouched(&mut member_socket, &["u_online", "type_start"]).await;
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn failed_kick_leaves_no_trace() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut admin_socket = joined(&app, &admin, group_id).await;
+    let mut member_socket = joined(&app, &member, group_id).await;
+
+    let failure = Failure::on_event(&app, group_id).await;
+    let ack = admin_socket.emit("kick", Some(json!(member.username))).await;
+    failure.remove().await;
+    assert_server_error(&ack);
+
+    assert_eq!(role(&app, group_id, &member).await.as_deref(), Some("user"));
+    assert_eq!(event_messages(&app, group_id).await, 0);
+
+    assert_untouched(&mut admin_socket, &["u_online"]).await;
+    assert_untouched(&mut member_socket, &["u_online", "type_start"]).await;
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn failed_leave_leaves_no_trace() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut admin_socket = joined(&app, &admin, group_id).await;
+    let mut member_socket = joined(&app, &member, group_id).await;
+
+    let failure = Failure::on_event(&app, group_id).await;
+    let ack = member_socket.emit("leave", None).await;
+    failure.remove().await;
+    assert_server_error(&ack);
+
+    assert_eq!(role(&app, group_id, &member).await.as_deref(), Some("user"));
+    assert_eq!(event_messages(&app, group_id).await, 0);
+
+    assert_untouched(&mut admin_socket, &["u_online", "type_start"]).await;
+    assert_untouched(&mut member_socket, &["u_online", "type_start"]).await;
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn failed_account_deletion_leaves_no_trace() {
+    let app = TestApp::spawn().await;
+    let user = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&user, &[&member]).await;
+
+    let mut user_socket = joined(&app, &user, group_id).await;
+    assert_eq!(user_socket.emit("message", Some(json!("hello"))).await, json!({ "ok": true }));
+
+    // Fails once the group was handed over and the messages are gone
+    let failure = Failure::on_user_delete(&app, user.id).await;
+    let (status, body) = app.request(Method::DELETE, "/api/user", &user, Some(json!({
+        "password": PASSWORD,
+        "messages": "delete",
+        "groups": "transfer",
+    }))).await;
+    failure.remove().await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{body}");
+
+    let users = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users WHERE id = $1")
+        .bind(user.id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(users, 1);
+    assert_eq!(role(&app, group_id, &user).await.as_deref(), Some("admin"));
+    assert_eq!(role(&app, group_id, &member).await.as_deref(), Some("user"));
+
+    let messages = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM messages WHERE sender_id = $1")
+        .bind(user.id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(messages, 1);
+
+    // Still signed in and connected
+    let (status, _) = app.request(Method::GET, "/api/user", &user, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_untouched(&mut user_socket, &["u_online", "message"]).await;
+}
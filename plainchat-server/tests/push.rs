@@ -0,0 +1,82 @@
+mod common;
+
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{Method, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use common::TestApp;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+const VAPID_PRIVATE_KEY: &str = "YlnZdvFrmGwsaUS7XcV8M8_oedZn83uPY6ouuzXJIws";
+// Keys of a browser subscription, the mock service never decrypts anything
+const P256DH: &str = "BMXzPMmxjOunajyVpobvhRWDeHJFZDP960UpoWkmjKuJCBPay67kMIx1R4n8I6cAEIOr8q7dqhgUlEBxCbDTyIw";
+const AUTH: &str = "Zf1TNBe9IB6ekWZf5355MA";
+
+// Push service accepting every notification, handing over their bodies
+async fn mock_push_service() -> (String, mpsc::UnboundedReceiver<Bytes>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let app = Router::new()
+        .route("/push/:id", post(|State(tx): State<mpsc::UnboundedSender<Bytes>>, body: Bytes| async move {
+            let _ = tx.send(body);
+            StatusCode::CREATED
+        }))
+        .with_state(tx);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    (format!("http://{addr}/push/{}", Uuid::new_v4()), rx)
+}
+
+// More than web-push encrypts, and more than enough failures to drop a
+// subscription if they were held against it
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn long_messages_are_pushed_and_keep_the_subscription() {
+    let app = TestApp::spawn_with(|config| {
+        config.push.vapid_private_key = Some(VAPID_PRIVATE_KEY.to_owned());
+        config.push.vapid_subject = Some("mailto:test@example.com".to_owned());
+        config.push.allow_local_endpoints = true;
+    }).await;
+    let sender = app.create_user().await;
+    let recipient = app.create_user().await;
+    let group_id = app.create_group(&sender, &[&recipient]).await;
+
+    let (endpoint, mut pushes) = mock_push_service().await;
+    let (status, body) = app.request(Method::POST, "/api/user/push-subscriptions", &recipient, Some(json!({
+        "endpoint": endpoint,
+        "keys": { "p256dh": P256DH, "auth": AUTH },
+    }))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+
+    let mut socket = app.connect(&sender).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+
+    let content = "é".repeat(3000);
+    assert!(content.len() > 3052);
+    for _ in 0..6 {
+        assert_eq!(socket.emit("message", Some(json!(content))).await, json!({ "ok": true }));
+        let push = tokio::time::timeout(Duration::from_secs(10), pushes.recv())
+            .await
+            .expect("Long message should be pushed")
+            .unwrap();
+        assert!(!push.is_empty());
+    }
+
+    // The delivery is recorded after the push service answered
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (failures, delivered) = sqlx::query_as::<_, (i32, bool)>(
+        "SELECT failures, last_used_at IS NOT NULL FROM push_subscriptions WHERE endpoint = $1")
+        .bind(&endpoint)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!((failures, delivered), (0, true));
+}
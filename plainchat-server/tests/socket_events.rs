@@ -0,0 +1,214 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{TestApp, TestSocket};
+use serde_json::{json, Value};
+
+const QUIET: Duration = Duration::from_millis(300);
+
+fn error_msg(ack: &Value) -> &str {
+    ack["error"]["msg"].as_str().unwrap_or_else(|| panic!("Expected an error ack, got {ack}"))
+}
+
+fn error_fields(ack: &Value) -> Vec<&str> {
+    ack["error"]["fields"]
+        .as_object()
+        .unwrap_or_else(|| panic!("Expected a validation error ack, got {ack}"))
+        .keys()
+        .map(String::as_str)
+        .collect()
+}
+
+// The socket has to survive whatever came before and still work
+async fn assert_usable(socket: &mut TestSocket, group_id: uuid::Uuid) {
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+    assert_eq!(socket.emit("message", Some(json!("still here"))).await, json!({ "ok": true }));
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn group_events_before_join_are_rejected() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut socket = app.connect(&admin).await;
+    let ban = json!({ "username": member.username });
+    for (event, data) in [
+        ("message", Some(json!("hello"))),
+        ("kick", Some(json!(member.username))),
+        ("ban", Some(ban)),
+        ("add_user", Some(json!(member.username))),
+        ("leave", None),
+        ("type_start", None),
+        ("pin", Some(json!(uuid::Uuid::new_v4()))),
+    ] {
+        let ack = socket.emit(event, data).await;
+        assert_eq!(error_msg(&ack), "Join a group first", "{event} before joining");
+    }
+
+    // Nothing happened to the member
+    let members = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM user_groups WHERE group_id = $1")
+        .bind(group_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(members, 2);
+
+    assert_usable(&mut socket, group_id).await;
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn events_about_strangers_are_rejected() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let stranger = app.create_user().await;
+    let group_id = app.create_group(&admin, &[]).await;
+    let other_group_id = app.create_group(&stranger, &[]).await;
+
+    let mut socket = app.connect(&admin).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+
+    let ack = socket.emit("kick", Some(json!(stranger.username))).await;
+    assert_eq!(error_msg(&ack), format!("Member {} does not exist.", stranger.username));
+
+    let ack = socket.emit("kick", Some(json!("nobody-at-all"))).await;
+    assert_eq!(error_msg(&ack), "Member nobody-at-all does not exist.");
+
+    let ack = socket.emit("ban", Some(json!({ "username": "nobody-at-all" }))).await;
+    assert_eq!(error_msg(&ack), "User nobody-at-all does not exist.");
+
+    let ack = socket.emit("join", Some(json!(other_group_id))).await;
+    assert_eq!(error_msg(&ack), "user is not eligible to access this method");
+
+    // The failed join left the socket in its group
+    assert_usable(&mut socket, group_id).await;
+    assert!(socket.events(QUIET).await.iter().all(|(event, _)| event != "kick" && event != "ban"));
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn invalid_ids_are_rejected() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let group_id = app.create_group(&admin, &[]).await;
+
+    let mut socket = app.connect(&admin).await;
+    for group in ["", "not-a-uuid", "1234", &format!("{group_id}x")] {
+        let ack = socket.emit("join", Some(json!(group))).await;
+        assert_eq!(error_fields(&ack), ["group_id"], "join {group:?}");
+    }
+
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+    for event in ["pin", "unpin"] {
+        let ack = socket.emit(event, Some(json!("not-a-uuid"))).await;
+        assert_eq!(error_fields(&ack), ["message_id"], "{event} with a bad id");
+    }
+
+    let ack = socket.emit("pin", Some(json!(uuid::Uuid::new_v4()))).await;
+    assert!(ack["error"].is_object(), "pin of an unknown message: {ack}");
+
+    assert_usable(&mut socket, group_id).await;
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn malformed_payloads_are_rejected() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut socket = app.connect(&admin).await;
+    for (event, data) in [
+        ("join", None),
+        ("join", Some(json!(42))),
+        ("join", Some(json!({ "id": group_id }))),
+        ("message", Some(json!(null))),
+        ("message", Some(json!(["hello"]))),
+        ("kick", Some(json!({ "username": member.username }))),
+        ("ban", Some(json!(member.username))),
+        ("ban", Some(json!({ "username": member.username, "duration_secs": "forever" }))),
+        ("pin", Some(json!(1))),
+        ("add_user", Some(json!(true))),
+    ] {
+        let ack = socket.emit(event, data.clone()).await;
+        assert_eq!(error_fields(&ack), ["payload"], "{event} with {data:?}");
+    }
+
+    // None of them got through
+    assert_usable(&mut socket, group_id).await;
+    let role = sqlx::query_scalar::<_, String>(
+        "SELECT role::text FROM user_groups WHERE group_id = $1 AND user_id = $2")
+        .bind(group_id)
+        .bind(member.id)
+        .fetch_optional(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(role.as_deref(), Some("user"));
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn members_cannot_moderate() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut socket = app.connect(&member).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+
+    for (event, data) in [
+        ("kick", json!(admin.username)),
+        ("ban", json!({ "username": admin.username })),
+    ] {
+        let ack = socket.emit(event, Some(data)).await;
+        assert_eq!(error_msg(&ack), "user is not eligible to access this method", "{event} by a member");
+    }
+
+    assert_usable(&mut socket, group_id).await;
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn mentions_reach_unmuted_members() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let member = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&member]).await;
+
+    let mut member_socket = app.connect(&member).await;
+    let mut socket = app.connect(&admin).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+    let content = format!("hi @{}", member.username);
+    assert_eq!(socket.emit("message", Some(json!(content))).await, json!({ "ok": true }));
+
+    let events = member_socket.events(QUIET).await;
+    assert!(events.iter().any(|(event, _)| event == "mentioned"), "{events:?}");
+}
+
+#[tokio::test]
+#[ignore = "needs postgres and redis"]
+async fn here_mentions_only_online_members() {
+    let app = TestApp::spawn().await;
+    let admin = app.create_user().await;
+    let online = app.create_user().await;
+    let offline = app.create_user().await;
+    let group_id = app.create_group(&admin, &[&online, &offline]).await;
+
+    let _online_socket = app.connect(&online).await;
+    let mut socket = app.connect(&admin).await;
+    assert_eq!(socket.emit("join", Some(json!(group_id))).await, json!({ "ok": true }));
+    assert_eq!(socket.emit("message", Some(json!("@here hello"))).await, json!({ "ok": true }));
+
+    let mentioned = sqlx::query_scalar::<_, uuid::Uuid>("SELECT user_id FROM mentions WHERE group_id = $1")
+        .bind(group_id)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(mentioned, [online.id]);
+}